@@ -1,10 +1,12 @@
 use mlua::{Error as LuaError, Lua, Table};
 
-use crate::error::{io_error, task_error, TaskError};
+use crate::error::{action_error, io_error, task_error, TaskError};
+use crate::task::context::{check_mode, record_change};
 use crate::{output, Result};
+use nix::unistd::{Group, User};
 use std::{io::Error as IoError, process::Command};
 
-use super::util::exit_status;
+use super::util::{self, exit_status};
 
 #[derive(Debug)]
 struct UserModDef {
@@ -153,6 +155,49 @@ fn modify_user(user: UserModDef) -> Result<(), TaskError> {
     Ok(())
 }
 
+/// Describes the settings `modify_user` would change for an existing user.
+fn user_changes(user: &UserModDef) -> Result<Vec<String>, LuaError> {
+    let existing = User::from_name(&user.name)
+        .map_err(|e| action_error(format!("user: {}", e)))?
+        .ok_or_else(|| action_error(format!("Unknown user {}", user.name)))?;
+    let mut changes = Vec::new();
+    if existing.shell.to_string_lossy() != user.shell {
+        changes.push(format!("shell {}", user.shell));
+    }
+    if let Some(comment) = &user.comment {
+        if existing.gecos.to_string_lossy() != *comment {
+            changes.push(format!("comment {}", comment));
+        }
+    }
+    if let Some(home_dir) = &user.home_dir {
+        if existing.dir.to_string_lossy() != *home_dir {
+            changes.push(format!("home_dir {}", home_dir));
+        }
+    }
+    if let Some(uid) = user.uid {
+        if existing.uid.as_raw() != uid {
+            changes.push(format!("uid {}", uid));
+        }
+    }
+    if let Some(g) = &user.primary_group {
+        let gid = Group::from_name(g)
+            .map_err(|e| action_error(format!("group: {}", e)))?
+            .map(|g| g.gid);
+        if gid != Some(existing.gid) {
+            changes.push(format!("group {}", g));
+        }
+    }
+    for g in user.groups.iter() {
+        let member = Group::from_name(g)
+            .map_err(|e| action_error(format!("group: {}", e)))?
+            .is_some_and(|g| g.gid == existing.gid || g.mem.contains(&user.name));
+        if !member {
+            changes.push(format!("add to group {}", g));
+        }
+    }
+    Ok(changes)
+}
+
 fn user_exists(name: &str) -> Result<bool, IoError> {
     let cmd = Command::new("id").arg(name).output()?;
     Ok(cmd.status.success())
@@ -205,13 +250,31 @@ fn group_exists(name: &str) -> Result<bool, IoError> {
 }
 
 pub fn user(lua: &Lua) -> Result<(), TaskError> {
-    let f = lua.create_function(|_ctx, (name, opts): (String, Table)| {
-        if user_exists(&name).map_err(io_error)? {
-            output!("Modify user {}", name);
-            modify_user(UserModDef::from_lua(name, opts)?).map_err(task_error)?;
+    let f = lua.create_function(|ctx, (name, opts): (String, Table)| {
+        let exists = user_exists(&name).map_err(io_error)?;
+        let def = UserModDef::from_lua(name, opts)?;
+        let changes = if exists {
+            user_changes(&def)?
+        } else {
+            Vec::new()
+        };
+        record_change(ctx, !exists || !changes.is_empty());
+        if check_mode(ctx) {
+            if !exists {
+                output!("Create user {}", def.name);
+                util::would_change(format!("create user {}", def.name));
+            } else {
+                output!("Modify user {}", def.name);
+                for change in changes {
+                    util::would_change(format!("set {}", change));
+                }
+            }
+        } else if exists {
+            output!("Modify user {}", def.name);
+            modify_user(def).map_err(task_error)?;
         } else {
-            output!("Create user {}", name);
-            create_user(UserModDef::from_lua(name, opts)?).map_err(task_error)?;
+            output!("Create user {}", def.name);
+            create_user(def).map_err(task_error)?;
         }
         Ok(())
     })?;
@@ -237,12 +300,31 @@ pub fn group(lua: &Lua) -> Result<(), TaskError> {
         } else {
             ctx.create_table()?
         };
-        if group_exists(&name).map_err(io_error)? {
-            output!("Modify group {}", name);
-            modify_group(GroupModDef::from_lua(name, opts)?).map_err(task_error)?;
+        let exists = group_exists(&name).map_err(io_error)?;
+        let def = GroupModDef::from_lua(name, opts)?;
+        let gid_changed = match def.gid {
+            Some(gid) if exists => Group::from_name(&def.name)
+                .map_err(|e| action_error(format!("group: {}", e)))?
+                .is_some_and(|g| g.gid.as_raw() != gid),
+            _ => false,
+        };
+        record_change(ctx, !exists || gid_changed);
+        if check_mode(ctx) {
+            if !exists {
+                output!("Create group {}", def.name);
+                util::would_change(format!("create group {}", def.name));
+            } else {
+                output!("Modify group {}", def.name);
+                if gid_changed {
+                    util::would_change(format!("set gid {}", def.gid.unwrap_or_default()));
+                }
+            }
+        } else if exists {
+            output!("Modify group {}", def.name);
+            modify_group(def).map_err(task_error)?;
         } else {
-            output!("Create group {}", name);
-            create_group(GroupModDef::from_lua(name, opts)?).map_err(task_error)?;
+            output!("Create group {}", def.name);
+            create_group(def).map_err(task_error)?;
         }
         Ok(())
     })?;
@@ -70,7 +70,7 @@ pub fn cancel(lua: &Lua) -> Result<(), TaskError> {
             output!("  {}", &m);
         }
         Ok(TaskResult::Incomplete(
-            crate::task::IncompleteReason::Skipped,
+            crate::task::IncompleteReason::Cancelled(msg),
         ))
     })?;
     lua.globals().set("cancel", f)?;
@@ -83,7 +83,7 @@ pub fn success(lua: &Lua) -> Result<(), TaskError> {
         if let Some(ref m) = msg {
             output!("  {}", &m);
         }
-        Ok(TaskResult::Success)
+        Ok(TaskResult::Unchanged)
     })?;
     lua.globals().set("success", f)?;
     Ok(())
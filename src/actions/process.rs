@@ -3,10 +3,26 @@ use std::{collections::HashMap, io::prelude::*};
 use mlua::{Lua, Table};
 use tempfile::NamedTempFile;
 
-use crate::actions::util::exec_streaming_process;
+use crate::actions::util::{self, exec_streaming_process};
 use crate::error::{action_error, io_error, TaskError};
+use crate::task::context::{check_mode, deadline, record_change};
 use crate::{indent_output, output, Result};
 
+/// In check mode, commands only run when marked `check_safe`. Returns the
+/// placeholder result for a command that was skipped instead.
+fn skip_in_check_mode(ctx: &Lua, opts: &Table) -> Result<Option<Table>, mlua::Error> {
+    if !check_mode(ctx) || opts.get::<Option<bool>>("check_safe")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    util::would_change("run command (skipped in check mode)");
+    let retval = ctx.create_table()?;
+    retval.set("status", 0)?;
+    retval.set("stdout", "")?;
+    retval.set("stderr", "")?;
+    retval.set("skipped", true)?;
+    Ok(Some(retval))
+}
+
 pub fn shell(lua: &Lua) -> Result<(), TaskError> {
     let f = lua.create_function(|ctx, (cmd, options): (String, Option<Table>)| {
         let opts = if let Some(o) = options {
@@ -16,6 +32,11 @@ pub fn shell(lua: &Lua) -> Result<(), TaskError> {
         };
 
         output!("exec [ {} ]:", &cmd);
+        // There's no telling what a command did, so running one always counts as a change
+        record_change(ctx, true);
+        if let Some(skipped) = skip_in_check_mode(ctx, &opts)? {
+            return Ok(skipped);
+        }
 
         let inherit_env = opts.get::<Option<bool>>("inherit_env")?.unwrap_or(true);
         let env = opts
@@ -37,8 +58,17 @@ pub fn shell(lua: &Lua) -> Result<(), TaskError> {
         temp_file.write_all(cmd.as_bytes()).map_err(io_error)?;
         let temp_path = temp_file.into_temp_path();
         sh_args.push(temp_path.to_str().unwrap().to_string());
-        let output =
-            exec_streaming_process(&sh, &sh_args, inherit_env, env, cwd, stdout, stderr, echo)?;
+        let output = exec_streaming_process(
+            &sh,
+            &sh_args,
+            inherit_env,
+            env,
+            cwd,
+            stdout,
+            stderr,
+            echo,
+            deadline(ctx),
+        )?;
 
         let retval = ctx.create_table()?;
         retval.set("status", output.status)?;
@@ -72,6 +102,11 @@ pub fn exec(lua: &Lua) -> Result<(), TaskError> {
             let args_display = &args.join(" ");
             output!("exec [ {} {} ]:", &cmd, &args_display);
         }
+        // There's no telling what a command did, so running one always counts as a change
+        record_change(ctx, true);
+        if let Some(skipped) = skip_in_check_mode(ctx, &opts)? {
+            return Ok(skipped);
+        }
         let inherit_env = opts.get::<Option<bool>>("inherit_env")?.unwrap_or(true);
         let env = opts
             .get::<Option<HashMap<String, String>>>("env")?
@@ -81,8 +116,17 @@ pub fn exec(lua: &Lua) -> Result<(), TaskError> {
         let stderr = opts.get::<Option<bool>>("stderr")?.unwrap_or(true);
         let echo = opts.get::<Option<bool>>("echo")?.unwrap_or(true);
         let ignore_exit = opts.get::<Option<bool>>("ignore_exit")?.unwrap_or(false);
-        let output =
-            exec_streaming_process(&cmd, args, inherit_env, env, cwd, stdout, stderr, echo)?;
+        let output = exec_streaming_process(
+            &cmd,
+            args,
+            inherit_env,
+            env,
+            cwd,
+            stdout,
+            stderr,
+            echo,
+            deadline(ctx),
+        )?;
         let retval = ctx.create_table()?;
         retval.set("status", output.status)?;
         retval.set("stdout", output.stdout)?;
@@ -4,12 +4,14 @@ use crate::{indent_output, tracker};
 
 use console::style;
 use mlua::{IntoLua, Lua, Table};
-use nix::unistd::{Gid, Group, Uid, User};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{Gid, Group, Pid, Uid, User};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os::unix::process::ExitStatusExt;
 use std::process::{ExitStatus, Stdio};
+use std::time::Instant;
 use std::{convert::TryInto, fs::File, io::prelude::*, path::Path};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::select;
@@ -96,6 +98,62 @@ pub(crate) fn run_chown_recursive(
     Ok(())
 }
 
+/// Whether `p` is missing or not already owned by the given user and group.
+pub(crate) fn owner_differs(p: &Path, user: Option<Uid>, group: Option<Gid>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match std::fs::metadata(p) {
+        Ok(m) => {
+            user.is_some_and(|u| u.as_raw() != m.uid())
+                || group.is_some_and(|g| g.as_raw() != m.gid())
+        }
+        Err(_) => true,
+    }
+}
+
+/// Like `owner_differs`, but also checks everything below `p`.
+pub(crate) fn owner_differs_recursive(p: &Path, user: Option<Uid>, group: Option<Gid>) -> bool {
+    if owner_differs(p, user, group) {
+        return true;
+    }
+    if std::fs::symlink_metadata(p).is_ok_and(|m| m.is_dir()) {
+        if let Ok(entries) = std::fs::read_dir(p) {
+            return entries
+                .flatten()
+                .any(|ent| owner_differs_recursive(&ent.path(), user, group));
+        }
+    }
+    false
+}
+
+/// Whether `p` is missing or its permission bits differ from `mode`.
+pub(crate) fn mode_differs(p: &Path, mode: u32) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::metadata(p) {
+        Ok(m) => m.permissions().mode() & 0o7777 != mode,
+        Err(_) => true,
+    }
+}
+
+/// Report a change that was not applied because the run is in check mode.
+pub(crate) fn would_change<D: std::fmt::Display>(msg: D) {
+    indent_output!(1, "{} {}", style("would").magenta(), msg);
+}
+
+/// Convert a single Lua value, e.g. a variable, to JSON. Values with no JSON
+/// equivalent, like functions, become `null`.
+pub(crate) fn lua_value_to_json(v: mlua::Value) -> Result<Value, TaskError> {
+    use mlua::Value as LuaValue;
+
+    Ok(match v {
+        LuaValue::Boolean(b) => Value::Bool(b),
+        LuaValue::Integer(i) => Value::Number(i.into()),
+        LuaValue::Number(n) => serde_json::Number::from_f64(n).map_or(Value::Null, Value::Number),
+        LuaValue::String(s) => Value::String(s.to_string_lossy()),
+        LuaValue::Table(t) => lua_table_to_json(t)?,
+        _ => Value::Null,
+    })
+}
+
 pub(crate) fn lua_table_to_json(tbl: Table) -> Result<Value, TaskError> {
     use mlua::Value as LuaValue;
     use serde_json::Value as JsonValue;
@@ -182,6 +240,7 @@ pub(crate) fn exec_streaming_process<A, I, S>(
     capture_stdout: bool,
     capture_stderr: bool,
     echo: bool,
+    deadline: Option<Instant>,
 ) -> Result<ProcessOutput, mlua::Error>
 where
     A: AsRef<Path>,
@@ -202,6 +261,11 @@ where
     p.stdout(Stdio::piped());
     p.stderr(Stdio::piped());
     p.stdin(Stdio::piped());
+    if deadline.is_some() {
+        // Own process group, so everything the command starts can be killed
+        // together when the deadline passes
+        p.process_group(0);
+    }
     tracker::tracker().suspend_bars();
     let handle = std::thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_multi_thread()
@@ -211,6 +275,14 @@ where
         let _ = handle.enter();
         let output = rt.block_on(async move {
             let mut child = p.spawn().map_err(io_error)?;
+            let pgid = child.id().map(|id| Pid::from_raw(id as i32));
+            let timeout = tokio::time::sleep_until(
+                deadline
+                    .map(tokio::time::Instant::from_std)
+                    .unwrap_or_else(tokio::time::Instant::now),
+            );
+            tokio::pin!(timeout);
+            let mut timed_out = false;
 
             let mut out_reader =
                 BufReader::new(child.stdout.take().expect("Could not open stdout on child"))
@@ -219,7 +291,7 @@ where
                 BufReader::new(child.stderr.take().expect("Could not open stderr on child"))
                     .lines();
 
-            let join_handle = handle.spawn(async move { child.wait().await.map_err(io_error) });
+            let mut join_handle = handle.spawn(async move { child.wait().await.map_err(io_error) });
             let mut stdout_lines = Vec::new();
             let mut stderr_lines = Vec::new();
             loop {
@@ -248,9 +320,23 @@ where
                             break;
                         }
                     },
+                    _ = &mut timeout, if deadline.is_some() => break,
                 }
             }
-            let res = join_handle.await.expect("Failed to join child")?;
+            let res = select! {
+                res = &mut join_handle => res,
+                _ = &mut timeout, if deadline.is_some() => {
+                    timed_out = true;
+                    if let Some(pgid) = pgid {
+                        let _ = killpg(pgid, Signal::SIGKILL);
+                    }
+                    join_handle.await
+                },
+            };
+            if timed_out {
+                return Err(error::action_error("Command killed, the task timed out"));
+            }
+            let res = res.expect("Failed to join child")?;
             let status = exit_status(&res);
             let stdout = stdout_lines.join("\n");
             let stderr = stderr_lines.join("\n");
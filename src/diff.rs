@@ -0,0 +1,36 @@
+use std::path::Path;
+
+use similar::TextDiff;
+
+/// Files larger than this are not diffed.
+const MAX_DIFF_BYTES: usize = 256 * 1024;
+
+/// Produce a unified diff between the old and new contents of `path`.
+/// Binary or oversized contents get a one-line summary instead.
+pub fn unified_diff(path: &Path, old: &[u8], new: &[u8]) -> String {
+    let path = path.to_string_lossy();
+    if old.len().max(new.len()) > MAX_DIFF_BYTES {
+        return format!(
+            "{}: too large to diff ({} -> {} bytes)",
+            path,
+            old.len(),
+            new.len()
+        );
+    }
+    let (old, new) = match (as_text(old), as_text(new)) {
+        (Some(o), Some(n)) => (o, n),
+        _ => return format!("{}: binary files differ", path),
+    };
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("{} (before)", path), &format!("{} (after)", path))
+        .to_string()
+}
+
+fn as_text(contents: &[u8]) -> Option<&str> {
+    if contents.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(contents).ok()
+}
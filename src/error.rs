@@ -1,15 +1,15 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use thiserror::Error;
 
-use crate::task::TaskHandle;
-
 #[derive(Debug, Error)]
 pub enum TaskError {
-    #[error("Cycle detected involving {0}")]
-    Cycle(TaskHandle),
-    #[error("Unknown task {0}")]
-    UnknownTask(TaskHandle),
+    #[error("Dependency cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("Task '{task}' depends on unknown task '{name}'")]
+    UnknownTask { task: String, name: String },
+    #[error("Invalid variables:\n{}", .0.iter().map(|p| format!("  - {}", p)).collect::<Vec<_>>().join("\n"))]
+    InvalidVars(Vec<String>),
     #[error("Lua Error: {0}")]
     Lua(#[from] mlua::Error),
     #[error("IO Error: {0}")]
@@ -22,6 +22,8 @@ pub enum TaskError {
     Template(#[from] tera::Error),
     #[error("Dbus error: {0}")]
     Dbus(#[from] zbus::Error),
+    #[error("Task timed out after {0:?}")]
+    Timeout(Duration),
 }
 
 #[derive(Debug, Error)]
@@ -36,6 +38,8 @@ pub enum HpgError {
     Parse(String),
     #[error("Serialization Error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("Host is locked, {0}. Use --wait-lock to wait for it")]
+    Locked(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -91,6 +95,8 @@ pub enum HpgRemoteError {
     RsyncDiffError(#[from] fast_rsync::DiffError),
     #[error("Unknown Error: {0}")]
     Unknown(String),
+    #[error("Remote hpg failed: {0}")]
+    ServerFailed(String),
     #[error("Exec Error: {0}")]
     ExecError(#[from] Box<HpgError>),
     #[error(transparent)]
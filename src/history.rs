@@ -0,0 +1,267 @@
+use std::{
+    fs::{DirBuilder, OpenOptions, Permissions},
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+use chrono::Local;
+use console::{pad_str, style, Alignment};
+use nix::unistd::geteuid;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::content_hash,
+    indent_output, lock, output, secrets,
+    task::{
+        report::{RunReport, TaskReport, TaskStatus},
+        Variables,
+    },
+    Result,
+};
+
+/// Where root keeps run history. Unlike the run lock it has to survive
+/// reboots. Only readable by the user running HPG, as outputs can hold
+/// anything.
+pub const HISTORY_DIR: &str = "/var/lib/hpg";
+
+/// Directory for run history. Other users can't write root's, so they keep
+/// their own in their state directory.
+fn history_dir() -> Result<PathBuf> {
+    if geteuid().is_root() {
+        return Ok(PathBuf::from(HISTORY_DIR));
+    }
+    lock::user_state_dir()
+        .ok_or_else(|| anyhow!("HOME isn't set, so there's nowhere to keep run history").into())
+}
+
+fn history_path() -> Result<PathBuf> {
+    Ok(history_dir()?.join("history.jsonl"))
+}
+
+/// What a run was given: the config and variables, as hashes, so runs with
+/// the same inputs can be told apart from ones without.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunInputs {
+    /// Path of the config file, relative to the project dir.
+    pub config: String,
+    pub config_hash: String,
+    pub vars_hash: String,
+}
+
+impl RunInputs {
+    pub fn new(config: &str, code: &str, vars: &Variables) -> Result<RunInputs> {
+        Ok(RunInputs {
+            config: config.to_string(),
+            config_hash: content_hash(code.as_bytes()),
+            vars_hash: content_hash(serde_json::to_string(vars)?.as_bytes()),
+        })
+    }
+}
+
+/// One run in the host's history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// When the run started, e.g. `20240102-150405.123`.
+    pub id: String,
+    pub user: String,
+    #[serde(flatten)]
+    pub inputs: RunInputs,
+    #[serde(flatten)]
+    pub report: RunReport,
+}
+
+impl RunRecord {
+    fn result(&self) -> String {
+        if self.report.check {
+            style("check").cyan().to_string()
+        } else if self.report.success {
+            style("success").green().to_string()
+        } else {
+            style("failed").red().to_string()
+        }
+    }
+
+    /// Every task and handler, in the order they ran.
+    fn all_tasks(&self) -> impl Iterator<Item = &TaskReport> {
+        self.report.tasks.iter().chain(self.report.handlers.iter())
+    }
+
+    fn changed_tasks(&self) -> usize {
+        self.all_tasks().filter(|t| t.changes.changed > 0).count()
+    }
+}
+
+/// Append the run to the host's history. Failing to record it is reported,
+/// but doesn't fail the run.
+pub fn record(inputs: RunInputs, report: &RunReport) {
+    let record = RunRecord {
+        id: report.start.format("%Y%m%d-%H%M%S%.3f").to_string(),
+        user: lock::current_user(),
+        inputs,
+        report: report.clone(),
+    };
+    if let Err(e) = append(&record) {
+        output!(
+            "{}",
+            style(format!("Couldn't record run in history: {}", e)).yellow()
+        );
+    }
+}
+
+fn append(record: &RunRecord) -> Result<()> {
+    let dir = history_dir()?;
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    // Also tightens a directory or file left readable by older versions
+    std::fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+    // Redacted as a whole, so a secret can't get in through any field
+    let record = secrets::redact_json(serde_json::to_value(record)?);
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    let mut f = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(history_path()?)?;
+    f.set_permissions(Permissions::from_mode(0o600))?;
+    f.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Every recorded run, oldest first.
+fn load() -> Result<Vec<RunRecord>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(std::fs::File::open(&path)?);
+    let mut records = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("{}, line {}: {}", path.to_string_lossy(), n + 1, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// List the last `limit` runs, newest first.
+pub fn list(limit: usize) -> Result<()> {
+    let records = load()?;
+    if records.is_empty() {
+        output!("No runs recorded on this host");
+        return Ok(());
+    }
+    let user_width = records
+        .iter()
+        .map(|r| r.user.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    output!(
+        "{}",
+        style(format!(
+            "{:<19}  {:<19}  {}  {:>8}  {:<7}  {:>7}  TARGETS",
+            "ID",
+            "STARTED",
+            pad_str("USER", user_width, Alignment::Left, None),
+            "DURATION",
+            "RESULT",
+            "CHANGED"
+        ))
+        .cyan()
+    );
+    for r in records.iter().rev().take(limit) {
+        output!(
+            "{:<19}  {}  {}  {:>7.0}s  {}  {:>7}  {}",
+            r.id,
+            r.report
+                .start
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            pad_str(&r.user, user_width, Alignment::Left, None),
+            r.report.duration,
+            pad_str(&r.result(), 7, Alignment::Left, None),
+            r.changed_tasks(),
+            r.report.targets.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn task_status(task: &TaskReport) -> String {
+    match &task.status {
+        TaskStatus::Success if task.changes.changed > 0 => style("changed").yellow().to_string(),
+        TaskStatus::Success => style("ok").green().to_string(),
+        TaskStatus::NotApplicable { .. } => style("not applicable").cyan().to_string(),
+        TaskStatus::Cancelled { .. } => style("cancelled").yellow().to_string(),
+        TaskStatus::Failed => style("failed").red().to_string(),
+        TaskStatus::Skipped => style("skipped").cyan().to_string(),
+        TaskStatus::NotRun => style("not run").dim().to_string(),
+    }
+}
+
+/// Show what a run did, or the whole record as JSON.
+pub fn show(id: &str, json: bool) -> Result<()> {
+    let record = load()?
+        .into_iter()
+        .rev()
+        .find(|r| r.id == id)
+        .ok_or_else(|| anyhow!("No run '{}' in history", id))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&record)?);
+        return Ok(());
+    }
+    let start = record.report.start.with_timezone(&Local);
+    output!("{} {}", style("Run").cyan(), record.id);
+    indent_output!(1, "User: {}", record.user);
+    indent_output!(
+        1,
+        "Started: {}, took {:.0}s",
+        start.format("%Y-%m-%d %H:%M:%S"),
+        record.report.duration
+    );
+    indent_output!(1, "Result: {}", record.result());
+    indent_output!(1, "Targets: {}", record.report.targets.join(", "));
+    indent_output!(
+        1,
+        "Config: {} ({})",
+        record.inputs.config,
+        &record.inputs.config_hash[..12]
+    );
+    indent_output!(1, "Variables: {}", &record.inputs.vars_hash[..12]);
+
+    output!("{}", style("Tasks").cyan());
+    for task in record.report.tasks.iter() {
+        indent_output!(1, "{}: {}", task.task, task_status(task));
+        if let Some(e) = &task.error {
+            indent_output!(2, "{}", style(e).red());
+        }
+    }
+    if !record.report.handlers.is_empty() {
+        output!("{}", style("Handlers").cyan());
+        for task in record.report.handlers.iter() {
+            indent_output!(1, "{}: {}", task.task, task_status(task));
+        }
+    }
+
+    let mut files: Vec<&String> = record.all_tasks().flat_map(|t| t.files.iter()).collect();
+    files.sort();
+    files.dedup();
+    if !files.is_empty() {
+        let heading = if record.report.check {
+            "Would Change"
+        } else {
+            "Changed Files"
+        };
+        output!("{}", style(heading).cyan());
+        for f in files {
+            indent_output!(1, "{}", f);
+        }
+    }
+    Ok(())
+}
@@ -4,21 +4,110 @@
 --- Task sigil type
 ---@class Task
 local Task = {}
+
+--- The table returned by this task's body, or `nil` if it hasn't run or didn't return a table.
+---@return table?
+function Task:output()
+end
+
+--- Task options, given as named fields alongside the dependencies, e.g. `{ dep, retries = 3 }`
+---@class TaskOptions
+---@field retries? integer Times to re-run the task after it fails, default 0
+---@field retry_delay? number Seconds to wait before the first retry, default 1
+---@field backoff? number Factor the delay is multiplied by after each retry, default 1
+---@field timeout? number Seconds an attempt may run before it's aborted and its commands killed
+---@field tags? string|string[] Tags for selecting the task with `--tags` and `--skip-tags`
+---@field when? fun(): boolean, string? Run the task only if this returns true; a second return value gives the reason when it doesn't
+
 --- Defines an HPG task. Task bodies are not evaluated until after task dependencies and execution order has been defined.
 ---@param description string Human description of the task
----@param dependency? Task|Task[] Other task names that must run before this one.
+---@param dependency? Task|string|(Task|string)[]|TaskOptions Other tasks (or the names of their globals) that must run before this one, with any task options.
 ---@param body? function Task body, code to run on task execution.
 ---@return Task Task object that can be used for dependencies
 ---@overload fun(description: string, body: function)
 function task(description, dependency, body)
 end
 
+--- Defines one task per item in a list, each run with its item. The tasks are named `<description>[<item>]`,
+--- with spaces and punctuation in the description replaced by `_`. Table items are named by their `name` field,
+--- or their position in the list.
+---@param description string Human description of the tasks
+---@param items any[] Items to define a task for
+---@param dependency? Task|string|(Task|string)[]|TaskOptions Dependencies and options shared by every task. `when` is called with the item.
+---@param body? fun(item: any) Task body, called with the item.
+---@return Task Task that depends on every item's task
+---@overload fun(description: string, items: any[], body: fun(item: any))
+function task_each(description, items, dependency, body)
+end
+
+--- Make tasks available to the command line under the given names. Tasks in nested tables are named `outer.inner`.
+---@param tasks table<string, Task|table> Tasks by name
+---@return table tasks The given table
+function export(tasks)
+end
+
+--- Load the role in `roles/<name>/`, running its `tasks.lua`. Relative paths given to `file()` and `dir()` in the
+--- role are relative to the role directory.
+---@param name string Role name
+---@param vars? table Variables for the role, on top of its `defaults.json`. Also passed to `tasks.lua` as its argument.
+---@return any ... Whatever the role's `tasks.lua` returns
+function role(name, vars)
+end
+
 --- Define tasks that should be run as default (via `hpg -D`).
 ---@param task string|Task Task name to register as a default.
 ---@vararg string
 function target(task, ...)
 end
 
+--- Handler sigil type
+---@class Handler
+---@field name string Handler name
+local Handler = {}
+--- Defines a handler. A handler only runs if a task notifies it, and then
+--- only once, after all requested tasks have finished.
+---@param name string Unique name of the handler
+---@param body function Handler body, code to run when notified.
+---@return Handler Handler object that can be passed to `notify()`
+function handler(name, body)
+end
+
+--- Notify a handler so it runs at the end of the run.
+--- If `changed` is given, the handler is only notified when it is `true`,
+--- so the result of an action can be passed straight through.
+---@param handler Handler|string Handler or handler name to notify
+---@param changed? boolean Only notify if true
+---@return boolean notified Whether the handler was notified
+function notify(handler, changed)
+end
+
+--- Run any handlers notified so far, instead of waiting for the end of the run.
+function flush_handlers()
+end
+
+--- Run report passed to `on_success()`, `on_failure()` and `on_finish()` callbacks, in the same shape as `--report`.
+---@class RunReport
+---@field success boolean Whether every task succeeded
+---@field check boolean Whether this was a check mode run
+---@field targets string[] Requested targets
+---@field tasks table[] Task results with `task`, `status`, `reason` and `error`, in execution order
+---@field handlers table[] Handler results
+
+--- Call `f` with the run report after a run where every task succeeded.
+---@param f fun(report: RunReport)
+function on_success(f)
+end
+
+--- Call `f` with the run report after a run where any task failed or was skipped.
+---@param f fun(report: RunReport)
+function on_failure(f)
+end
+
+--- Call `f` with the run report after every run, after any `on_success()` or `on_failure()` callbacks.
+---@param f fun(report: RunReport)
+function on_finish(f)
+end
+
 --- Creates a sigil that marks task success.
 --- use `return success()` in a task to immediately succeed the task.
 ---@return userdata sigil success marker
@@ -41,11 +130,28 @@ end
 function fail(reason)
 end
 
+--- Declaration of a variable passed to `declare_vars()`.
+---@class VarDeclaration
+---@field type? "string"|"integer"|"number"|"boolean"|"table"|"list" Type the value must have. Any type if not given.
+---@field required? boolean Fail before the run if the variable isn't set
+---@field default? any Default value, used like a value assigned to `vars`
+---@field description? string Shown by `--list-vars`
+
+--- Declare the variables the config expects. They're checked before any task runs, and every problem is reported
+--- at once. Declaring a variable again replaces the earlier declaration.
+---@param decls table<string, VarDeclaration> Declarations by variable name
+function declare_vars(decls)
+end
+
 --- Hpg variables that were passed in via file or command line.
 --- Values assigned inside a lua file will be considered defaults, and will be used with less precedence than passed-in variables.
 ---@type table
 vars = {}
 
+--- Outputs of tasks that have finished, by task name: the table each task body returned.
+---@type table<string, table>
+outputs = {}
+
 --- Create a new instance of a Dir object.
 --- Runtime error raised if path exists and is not a directory.
 ---@param path string Directory path in unix format, relative to current working directory.
@@ -344,11 +450,13 @@ end
 ---@field stderr? boolean Capture stderr of the process. Default `true`.
 ---@field echo? boolean Echo stdout and stderr of process to HPG's stdout. Default `true`.
 ---@field ignore_exit? boolean If `true`, will not halt task execution on nonzero exit status.
+---@field check_safe? boolean If `true`, the command still runs in check mode. Only set this for commands without side effects.
 
 ---@class ExitStatus
 ---@field status number Numeric exit status of process.
 ---@field stdout string Stdout output of process.
 ---@field stderr string Stderr output of process.
+---@field skipped? boolean `true` if the command was skipped because of check mode.
 
 --- Run an executable as a subprocess.
 ---@param cmd string Path to executable.
@@ -375,6 +483,7 @@ end
 ---@field stderr? boolean Capture stderr of the process. Default `true`.
 ---@field echo? boolean Echo stdout and stderr of process to HPG's stdout. Default `true`.
 ---@field ignore_exit? boolean If `true`, will not halt task execution on nonzero exit status.
+---@field check_safe? boolean If `true`, the command still runs in check mode. Only set this for commands without side effects.
 ---@field sh? string Shell to run this command with. Default `/bin/sh`.
 ---@field sh_args? string[] Extra arguments to pass to the shell.
 
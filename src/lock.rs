@@ -0,0 +1,187 @@
+use std::{
+    fs::{DirBuilder, File, Metadata, OpenOptions},
+    io::{Read, Seek, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local, Utc};
+use console::style;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg, OFlag},
+    unistd::{geteuid, getpid, getuid, User},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::HpgError, output, Result};
+
+/// Where root keeps the run lock. Only root can create it, so nobody else
+/// can plant a file or link there for a root run to open.
+pub const LOCK_DIR: &str = "/run/hpg";
+
+/// Per-user directory for state kept across runs, `$XDG_STATE_HOME/hpg` or
+/// `~/.local/state/hpg`. `None` if neither variable is set.
+pub fn user_state_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .filter(|d| !d.is_empty())
+                .map(|h| PathBuf::from(h).join(".local/state"))
+        })
+        .map(|d| d.join("hpg"))
+}
+
+/// Directory for the run lock. Runs as other users can't share root's, so
+/// they lock in their own runtime directory instead, or their state
+/// directory if there isn't one.
+fn lock_dir() -> Result<PathBuf> {
+    if geteuid().is_root() {
+        return Ok(PathBuf::from(LOCK_DIR));
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|d| !d.is_empty())
+        .map(|d| PathBuf::from(d).join("hpg"))
+        .or_else(user_state_dir)
+        .ok_or_else(|| {
+            anyhow!(
+                "Neither XDG_RUNTIME_DIR nor HOME is set, so there's nowhere safe to keep the run lock. Run as root, or set one"
+            )
+            .into()
+        })
+}
+
+/// Who holds the run lock, written to the lock file once it's taken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub user: String,
+    pub since: DateTime<Utc>,
+    pub pid: i32,
+}
+
+/// Name of the user running HPG. Runs over SSH are usually under sudo, so
+/// the user behind sudo is preferred.
+pub fn current_user() -> String {
+    std::env::var("SUDO_USER").ok().unwrap_or_else(|| {
+        User::from_uid(getuid())
+            .ok()
+            .flatten()
+            .map(|u| u.name)
+            .unwrap_or_else(|| getuid().to_string())
+    })
+}
+
+impl LockHolder {
+    fn current() -> LockHolder {
+        LockHolder {
+            user: current_user(),
+            since: Utc::now(),
+            pid: getpid().as_raw(),
+        }
+    }
+
+    fn read(file: &mut File) -> Option<LockHolder> {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Describe the run holding the lock, if it could be read.
+    fn describe(holder: Option<LockHolder>) -> String {
+        match holder {
+            Some(h) => format!(
+                "run in progress by {} since {}, pid {}",
+                h.user,
+                h.since.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                h.pid
+            ),
+            None => "run in progress by another process".to_string(),
+        }
+    }
+}
+
+/// Exclusive lock on the host, so only one `local` or `server` run changes
+/// it at a time. Released when dropped, or when the process exits.
+#[derive(Debug)]
+pub struct RunLock {
+    _lock: Flock<File>,
+}
+
+impl RunLock {
+    /// Take the run lock. If another run holds it, fails with who holds it,
+    /// or with `wait`, blocks until it's released.
+    pub fn acquire(wait: bool) -> Result<RunLock> {
+        let file = open_lock_file()?;
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(l) => l,
+            Err((mut file, Errno::EWOULDBLOCK)) => {
+                let holder = LockHolder::describe(LockHolder::read(&mut file));
+                if !wait {
+                    return Err(HpgError::Locked(holder));
+                }
+                output!(
+                    "{}",
+                    style(format!("Waiting for lock, {}", holder)).yellow()
+                );
+                Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, e)| io_err(e))?
+            }
+            Err((_, e)) => return Err(io_err(e)),
+        };
+        // Recording the holder is best effort, the lock is what counts
+        let holder = serde_json::to_string(&LockHolder::current())?;
+        if file.set_len(0).is_ok() {
+            let _ = file.rewind();
+            let _ = file.write_all(holder.as_bytes());
+        }
+        Ok(RunLock { _lock: file })
+    }
+}
+
+fn io_err(e: Errno) -> HpgError {
+    HpgError::File(std::io::Error::from(e))
+}
+
+/// Fail unless `meta` is owned by us and can't be written by anyone else.
+fn check_owner(path: &Path, meta: &Metadata) -> Result<()> {
+    if meta.uid() != geteuid().as_raw() || meta.mode() & 0o022 != 0 {
+        return Err(anyhow!(
+            "{} must be owned by uid {} and not writable by others",
+            path.to_string_lossy(),
+            geteuid()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Open the lock file, creating it and its directory if needed. Neither is
+/// followed if it's a symlink, and both have to belong to us.
+fn open_lock_file() -> Result<File> {
+    let dir = lock_dir()?;
+    if std::fs::symlink_metadata(&dir).is_err() {
+        DirBuilder::new().recursive(true).mode(0o755).create(&dir)?;
+    }
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.to_string_lossy()).into());
+    }
+    check_owner(&dir, &meta)?;
+    let path = dir.join("run.lock");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(&path)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(anyhow!("{} is not a regular file", path.to_string_lossy()).into());
+    }
+    check_owner(&path, &meta)?;
+    Ok(file)
+}
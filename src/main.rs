@@ -3,27 +3,32 @@ use clap::Args;
 use clap::CommandFactory;
 use clap::Parser;
 use clap::Subcommand;
-use console::style;
 use error::HpgError;
 
 use remote::config::InventoryConfig;
 use remote::ssh::HostInfo;
 
-use std::collections::HashMap;
 use std::fs::File;
 
 use std::path::PathBuf;
 
+use task::graph::GraphFormat;
+use task::vars::{ListMerge, MapMerge, MergeStrategy};
+use task::ExecOptions;
 use task::LuaState;
 use task::Variables;
 
 pub(crate) mod actions;
+mod diff;
 mod error;
 mod hash;
+mod history;
+mod lock;
 mod macros;
 pub(crate) mod modules;
 
 mod remote;
+mod secrets;
 mod task;
 mod tracker;
 
@@ -39,11 +44,28 @@ pub fn load_file(fname: &str) -> Result<String, HpgError> {
     Ok(s)
 }
 
-fn parse_variable(s: &str) -> Result<(String, String), String> {
+/// Parse `KEY=VALUE`, where VALUE is a string, or `KEY:=VALUE`, where VALUE
+/// is JSON, e.g. `count:=3` or `flags:=["a", "b"]`.
+fn parse_variable(s: &str) -> Result<(String, serde_json::Value), String> {
     let (k, v) = s
         .split_once('=')
         .ok_or_else(|| "Invalid Variable: Missing '='".to_string())?;
-    Ok((k.to_string(), v.to_string()))
+    match k.strip_suffix(':') {
+        Some(k) => {
+            let v =
+                serde_json::from_str(v).map_err(|e| format!("Invalid value for '{}': {}", k, e))?;
+            Ok((k.to_string(), v))
+        }
+        None => Ok((k.to_string(), serde_json::Value::String(v.to_string()))),
+    }
+}
+
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    let jobs: usize = s.parse().map_err(|e| format!("Invalid job count: {e}"))?;
+    if jobs == 0 {
+        return Err("Job count must be at least 1".to_string());
+    }
+    Ok(jobs)
 }
 
 fn try_parse_host(host_str: &str) -> Result<HostInfo, String> {
@@ -123,10 +145,125 @@ enum RemoteCommands {
         #[command(flatten)]
         hpg_opts: HpgOpt,
     },
+    #[command(about = "Show the variables a run would get, and where they come from")]
+    Vars {
+        #[arg(short, long, name = "INVENTORY", help = "Path to inventory file")]
+        inventory: Option<String>,
+        #[arg(
+            long,
+            name = "HOST",
+            help = "Include variables from the inventory for HOST, as `hpg ssh HOST` would"
+        )]
+        host: Option<String>,
+        #[arg(
+            short,
+            long,
+            name = "CONFIG",
+            default_value = "hpg.lua",
+            help = "Path to hpg config file, relative to project-dir"
+        )]
+        config: String,
+        #[arg(
+            short,
+            long,
+            help = "Path to project root. Default is the current directory",
+            required = false,
+            default_value = ".",
+            value_parser(ProjectDirParser::new())
+        )]
+        project_dir: PathBuf,
+        #[command(flatten)]
+        var_opts: VarOpt,
+        #[arg(long, help = "Show every value with the source it came from")]
+        explain: bool,
+        #[arg(
+            name = "KEY",
+            help = "Only show this variable, or a dotted path into it"
+        )]
+        key: Option<String>,
+    },
+    #[command(about = "Show runs recorded on this host")]
+    History {
+        #[command(subcommand)]
+        cmd: Option<HistoryCommands>,
+        #[arg(
+            short = 'n',
+            long,
+            default_value = "20",
+            help = "Number of runs to list"
+        )]
+        limit: usize,
+    },
+    #[command(about = "Encrypt and decrypt secret variables")]
+    Secret {
+        #[arg(
+            long,
+            name = "KEY-FILE",
+            global = true,
+            help = "File holding the passphrase. Default is $HPG_SECRET_KEY_FILE, then $HPG_SECRET_PASSPHRASE, then a prompt"
+        )]
+        key_file: Option<PathBuf>,
+        #[command(subcommand)]
+        cmd: SecretCommands,
+    },
     #[command(hide(true))]
     Server {
         #[arg(name = "ROOT-DIR", help = "Base dir for HPG sync")]
         root_dir: String,
+        #[arg(long, help = "Wait for another run to finish instead of failing")]
+        wait_lock: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum HistoryCommands {
+    #[command(about = "Show the tasks and changed files of a run")]
+    Show {
+        #[arg(name = "RUN-ID", help = "Run ID, as listed by `hpg history`")]
+        id: String,
+        #[arg(long, help = "Print the whole record as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SecretCommands {
+    #[command(about = "Print an encrypted value for a variables file, or encrypt a whole file")]
+    Encrypt {
+        #[arg(
+            short,
+            long,
+            name = "FILE",
+            conflicts_with = "VALUE",
+            help = "Encrypt FILE in place"
+        )]
+        file: Option<PathBuf>,
+        #[arg(
+            name = "VALUE",
+            help = "Value to encrypt. Read from stdin if not given"
+        )]
+        value: Option<String>,
+    },
+    #[command(about = "Print a decrypted value or file")]
+    Decrypt {
+        #[arg(
+            short,
+            long,
+            name = "FILE",
+            conflicts_with = "VALUE",
+            help = "Decrypt FILE"
+        )]
+        file: Option<PathBuf>,
+        #[arg(
+            name = "VALUE",
+            help = "Value to decrypt. Read from stdin if not given"
+        )]
+        value: Option<String>,
+    },
+    #[command(about = "Edit an encrypted file in $EDITOR, creating it if it doesn't exist")]
+    Edit {
+        #[arg(name = "FILE")]
+        file: PathBuf,
     },
 }
 
@@ -146,6 +283,54 @@ struct GlobalOpt {
     debug: bool,
 }
 
+#[derive(Debug, Args)]
+pub struct VarOpt {
+    #[arg(
+        short = 'v',
+        long = "var",
+        name = "KEY=VALUE",
+        help = "Key-value pairs to add as variables. Use KEY:=VALUE to give VALUE as JSON",
+        value_parser(parse_variable)
+    )]
+    variables: Vec<(String, serde_json::Value)>,
+    #[arg(
+        long = "vars",
+        name = "VARS-FILE",
+        help = "Path to variables file, in JSON, TOML (.toml) or YAML (.yaml, .yml)"
+    )]
+    var_file: Vec<String>,
+    #[arg(
+        long,
+        value_enum,
+        name = "MAPS",
+        help = "How tables from different variable sources are merged [default: deep]"
+    )]
+    merge_maps: Option<MapMerge>,
+    #[arg(
+        long,
+        value_enum,
+        name = "LISTS",
+        help = "How lists from different variable sources are merged [default: replace]"
+    )]
+    merge_lists: Option<ListMerge>,
+    #[arg(
+        long,
+        name = "KEY-FILE",
+        help = "File holding the passphrase for encrypted variables. Default is $HPG_SECRET_KEY_FILE"
+    )]
+    secret_key_file: Option<PathBuf>,
+}
+
+impl VarOpt {
+    /// `base` (e.g. from the inventory), with the options given on the command line.
+    fn merge_strategy(&self, base: MergeStrategy) -> MergeStrategy {
+        MergeStrategy {
+            maps: self.merge_maps.unwrap_or(base.maps),
+            lists: self.merge_lists.unwrap_or(base.lists),
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 pub struct HpgOpt {
     #[arg(
@@ -172,40 +357,127 @@ pub struct HpgOpt {
         help = "Run default targets in config"
     )]
     run_defaults: bool,
-    #[arg(
-        short = 'v',
-        long = "var",
-        name = "KEY=VALUE",
-        help = "Key-value pairs to add as variables",
-        value_parser(parse_variable)
-    )]
-    variables: Vec<(String, String)>,
-    #[arg(
-        long = "vars",
-        name = "VARS-FILE",
-        help = "Path to JSON variables file"
-    )]
-    var_file: Vec<String>,
+    #[command(flatten)]
+    var_opts: VarOpt,
     #[arg(short, long, help = "Show planned execution but do not execute")]
     show: bool,
     #[arg(short, long, help = "Show available targets")]
     list: bool,
+    #[arg(
+        long,
+        value_enum,
+        name = "FORMAT",
+        help = "Print the dependency graph of TARGETS, or of all tasks, instead of running them",
+        conflicts_with_all = ["show", "list"]
+    )]
+    graph: Option<GraphFormat>,
+    #[arg(
+        long,
+        help = "Show declared variables and their values",
+        conflicts_with_all = ["show", "list", "FORMAT"]
+    )]
+    list_vars: bool,
+    #[arg(
+        long,
+        help = "Report what would change without changing anything",
+        conflicts_with = "show"
+    )]
+    check: bool,
+    #[arg(long, help = "Show a diff of every file that is changed")]
+    diff: bool,
+    #[arg(long, help = "Run notified handlers even if a task failed")]
+    force_handlers: bool,
+    #[arg(
+        short,
+        long,
+        help = "Keep running tasks that don't depend on a failed task"
+    )]
+    keep_going: bool,
+    #[arg(
+        long,
+        name = "TASK",
+        help = "Skip every task before TASK in the execution order"
+    )]
+    start_at: Option<String>,
+    #[arg(
+        long,
+        help = "Only run tasks that didn't complete in the last run, using its targets if none are given"
+    )]
+    resume: bool,
+    #[arg(
+        long,
+        help = "Ask before running each task whether to continue, skip it or abort",
+        conflicts_with = "jobs"
+    )]
+    step: bool,
+    #[arg(
+        long,
+        help = "Wait for another run on the host to finish instead of failing"
+    )]
+    wait_lock: bool,
+    #[arg(long, name = "PATH", help = "Write a JSON report of the run to PATH")]
+    report: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        default_value = "1",
+        help = "Number of independent tasks to run concurrently. Each worker evaluates the config separately",
+        value_parser(parse_jobs)
+    )]
+    jobs: usize,
+    #[arg(
+        long,
+        name = "TAG",
+        value_delimiter = ',',
+        help = "Also run every task tagged TAG, with its dependencies"
+    )]
+    tags: Vec<String>,
+    #[arg(
+        long,
+        name = "SKIP-TAG",
+        value_delimiter = ',',
+        help = "Leave out tasks tagged SKIP-TAG"
+    )]
+    skip_tags: Vec<String>,
     #[arg(name = "TARGETS", help = "Task names to run")]
     targets: Vec<String>,
 }
 
+impl HpgOpt {
+    fn exec_options(&self) -> ExecOptions {
+        ExecOptions {
+            run_defaults: self.run_defaults,
+            show_plan: self.show,
+            jobs: self.jobs,
+            check: self.check,
+            diff: self.diff,
+            force_handlers: self.force_handlers,
+            keep_going: self.keep_going,
+            start_at: self.start_at.clone(),
+            resume: self.resume,
+            tags: self.tags.clone(),
+            skip_tags: self.skip_tags.clone(),
+            step: self.step,
+        }
+    }
+}
+
 fn lsp_defs() -> &'static str {
     include_str!("hpgdefs.lua")
 }
 
-fn parse_variables(opt: &HpgOpt) -> Result<Variables> {
-    let vars: HashMap<String, String> = opt.variables.clone().into_iter().collect();
-    let mut v = Variables::from_map(&vars)?;
-
-    for f in opt.var_file.iter() {
-        let file_vars = Variables::from_file(f)?;
-        v = file_vars.merge(v)?;
+fn parse_variables(opt: &VarOpt, strategy: MergeStrategy) -> Result<Variables> {
+    secrets::set_key_file(opt.secret_key_file.clone());
+    let mut v = Variables::default();
+    // Earlier files take precedence over later ones
+    for f in opt.var_file.iter().rev() {
+        let file_vars = Variables::from_file(f)?.with_source(&format!("--vars {}", f));
+        v = v.merge(file_vars, strategy)?;
     }
+    let vars: serde_json::Map<String, serde_json::Value> =
+        opt.variables.clone().into_iter().collect();
+    let vars = Variables::from_json_with_secrets(serde_json::Value::Object(vars))?;
+    v = v.merge(vars.with_source("-v"), strategy)?;
     Ok(v)
 }
 
@@ -219,21 +491,51 @@ fn try_inventory_files(paths: &[&str]) -> Result<InventoryConfig> {
     Ok(InventoryConfig::default())
 }
 
+fn load_inventory(path: Option<String>) -> Result<InventoryConfig> {
+    if let Some(p) = path {
+        try_inventory_files(&[&p])
+    } else {
+        try_inventory_files(&["inventory.toml"])
+    }
+}
+
 fn run_hpg_local(opt: HpgOpt, lua: LuaState) -> Result<()> {
+    // Resolve the report path before moving into the project dir
+    let report_path = opt.report.as_deref().map(std::path::absolute).transpose()?;
+    // Listing and planning don't change anything, so they don't need the lock
+    let _lock = if opt.list || opt.list_vars || opt.show || opt.graph.is_some() {
+        None
+    } else {
+        Some(lock::RunLock::acquire(opt.wait_lock)?)
+    };
     std::env::set_current_dir(&opt.project_dir)?;
-    let vars = parse_variables(&opt)?;
+    let strategy = opt.var_opts.merge_strategy(MergeStrategy::default());
+    let vars = parse_variables(&opt.var_opts, strategy)?;
     let code = load_file(&opt.config)?;
+    let inputs = history::RunInputs::new(&opt.config, &code, &vars)?;
 
     let lua = lua.eval(&code, vars)?;
     if opt.list {
-        output!("{}", style("Available Tasks").cyan());
-        for (name, task) in lua.available_targets() {
-            indent_output!(1, "{}: {}", style(name).green(), task.description());
-        }
+        lua.list_targets();
+        return Ok(());
+    }
+    if opt.list_vars {
+        lua.list_vars()?;
         return Ok(());
     }
     let requested_tasks: Vec<&str> = opt.targets.iter().map(|t| t.as_str()).collect();
-    lua.execute(&requested_tasks, opt.run_defaults, opt.show)?;
+    if let Some(format) = opt.graph {
+        let graph = lua.graph(&requested_tasks, &opt.exec_options(), format)?;
+        output!("{}", graph.trim_end());
+        return Ok(());
+    }
+    if let Some(report) = lua.execute(&requested_tasks, &opt.exec_options())? {
+        history::record(inputs, &report);
+        if let Some(path) = &report_path {
+            report.save(path)?;
+        }
+        report.result()?;
+    }
 
     Ok(())
 }
@@ -256,7 +558,7 @@ fn run_hpg() -> Result<()> {
         return Ok(());
     }
 
-    let lua = LuaState::new()?;
+    let mut lua = LuaState::new()?;
     lua.register_fn(actions::echo)?;
     lua.register_fn(actions::fail)?;
     lua.register_fn(actions::exec)?;
@@ -294,18 +596,70 @@ fn run_hpg() -> Result<()> {
             inventory,
         }) => {
             let handle = tracker::init(opt.globals.debug)?;
-            let inventory = if let Some(p) = inventory {
-                try_inventory_files(&[&p])?
+            let inventory = load_inventory(inventory)?;
+            let strategy = hpg_opts.var_opts.merge_strategy(inventory.merge);
+            let vars = parse_variables(&hpg_opts.var_opts, strategy)?;
+            let succeeded = remote::ssh::run_hpg_ssh(host, hpg_opts, vars, inventory)?;
+            handle.finish();
+            if !succeeded {
+                return Err(error::TaskError::SkippedTask.into());
+            }
+            Ok(())
+        }
+        Some(RemoteCommands::Vars {
+            inventory,
+            host,
+            config,
+            project_dir,
+            var_opts,
+            explain,
+            key,
+        }) => {
+            let handle = tracker::init(opt.globals.debug)?;
+            // Variables files are found the same way as by the command being explained
+            let vars = if let Some(host) = host {
+                let inventory = load_inventory(inventory)?;
+                let strategy = var_opts.merge_strategy(inventory.merge);
+                let vars = parse_variables(&var_opts, strategy)?;
+                let host_config = inventory.config_for_host(&host);
+                let vars = remote::ssh::merge_vars(vars, &host_config, &inventory, strategy)?;
+                std::env::set_current_dir(&project_dir)?;
+                vars
             } else {
-                try_inventory_files(&["inventory.toml"])?
+                std::env::set_current_dir(&project_dir)?;
+                parse_variables(&var_opts, var_opts.merge_strategy(MergeStrategy::default()))?
             };
-            let vars = parse_variables(&hpg_opts)?;
-            remote::ssh::run_hpg_ssh(host, hpg_opts, vars, inventory)?;
+            let code = load_file(&config)?;
+            let res = lua
+                .eval(&code, vars)
+                .and_then(|lua| Ok(lua.show_vars(key.as_deref(), explain)?));
             handle.finish();
-            Ok(())
+            res
+        }
+        Some(RemoteCommands::Secret { key_file, cmd }) => {
+            secrets::set_key_file(key_file);
+            match cmd {
+                SecretCommands::Encrypt { file, value } => secrets::encrypt_command(value, file),
+                SecretCommands::Decrypt { file, value } => secrets::decrypt_command(value, file),
+                SecretCommands::Edit { file } => secrets::edit_command(&file),
+            }
         }
-        Some(RemoteCommands::Server { root_dir }) => {
+        Some(RemoteCommands::History { cmd, limit }) => {
+            let handle = tracker::init(opt.globals.debug)?;
+            let res = match cmd {
+                Some(HistoryCommands::Show { id, json }) => history::show(&id, json),
+                None => history::list(limit),
+            };
+            handle.finish();
+            res
+        }
+        Some(RemoteCommands::Server {
+            root_dir,
+            wait_lock,
+        }) => {
             let handle = tracker::init(opt.globals.debug)?;
+            // Held for the whole server run, covering the socket and the synced tree
+            let _lock = lock::RunLock::acquire(wait_lock)?;
             remote::server::run_socket_server(root_dir, lua, &PathBuf::from("/tmp/hpg.socket"))?;
             handle.finish();
             Ok(())
@@ -321,14 +675,16 @@ fn main() -> Result<()> {
     if let Err(e) = run_hpg() {
         match e {
             HpgError::Task(t) => match t {
-                error::TaskError::Cycle(c) => eprintln!("Cycle detected in task {}", c),
-                error::TaskError::UnknownTask(t) => eprintln!("Unknown task: {}", t),
+                e @ (error::TaskError::Cycle(_)
+                | error::TaskError::UnknownTask { .. }
+                | error::TaskError::InvalidVars(_)) => eprintln!("{}", e),
                 error::TaskError::Lua(l) => eprintln!("Lua Error: {}", l),
                 error::TaskError::Io(i) => eprintln!("IO Error: {}", i),
                 error::TaskError::Action(a) => eprintln!("Error in action: {}", a),
                 error::TaskError::SkippedTask => {}
                 error::TaskError::Template(t) => eprintln!("Error in template: {}", t),
                 error::TaskError::Dbus(d) => eprintln!("Dbus error: {}", d),
+                error::TaskError::Timeout(d) => eprintln!("Task timed out after {:?}", d),
             },
             HpgError::Remote(r) => {
                 eprintln!("Remote Error: {}", r);
@@ -336,8 +692,10 @@ fn main() -> Result<()> {
             HpgError::File(f) => eprintln!("Error loading file: {}", f),
             HpgError::Parse(p) => eprintln!("Failed parsing: {}", p),
             HpgError::Serde(e) => eprintln!("Failed to parse json: {}", e),
+            e @ HpgError::Locked(_) => eprintln!("{}", e),
             HpgError::Other(e) => eprintln!("{}", e),
         }
+        std::process::exit(1);
     }
     Ok(())
 }
@@ -7,7 +7,9 @@ use zip::ZipArchive;
 
 use mlua::{Lua, Table, UserData};
 
+use crate::actions::util;
 use crate::error::{self, TaskError};
+use crate::task::context::{check_mode, record_file_change};
 use crate::{output, Result};
 
 use super::file::HpgDir;
@@ -100,13 +102,18 @@ impl HpgArchive {
 
 impl UserData for HpgArchive {
     fn add_methods<T: mlua::UserDataMethods<Self>>(methods: &mut T) {
-        methods.add_method("extract", |_ctx, this, dst: String| {
+        methods.add_method("extract", |ctx, this, dst: String| {
             let dst = Path::new(".").join(dst);
             output!(
                 "Extract {} to {}",
                 &this.path.to_string_lossy(),
                 &dst.to_string_lossy()
             );
+            record_file_change(ctx, &dst, true);
+            if check_mode(ctx) {
+                util::would_change(format!("extract to {}", &dst.to_string_lossy()));
+                return Ok(HpgDir::new(&dst));
+            }
             this.extract(&dst)
         });
     }
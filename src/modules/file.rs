@@ -10,8 +10,12 @@ use nix::unistd::{geteuid, User};
 
 use crate::{
     actions::util,
+    diff,
     error::{self, TaskError},
-    hash, indent_output, output, Result,
+    hash, indent_output, output,
+    task::context::{check_mode, diff_mode, record_file_change},
+    tracker::{self, Tracker},
+    Result,
 };
 
 pub struct HpgFile {
@@ -58,7 +62,7 @@ impl UserData for HpgFile {
         methods.add_method("hash", |_, this, _: ()| {
             hash::file_hash(&this.path).map_err(error::io_error)
         });
-        methods.add_method("chown", |_, this, opts: Table| {
+        methods.add_method("chown", |ctx, this, opts: Table| {
             let user: Option<mlua::Value> = opts.get("user")?;
             let group: Option<mlua::Value> = opts.get("group")?;
             output!("Chown {}:", &this.path.to_string_lossy());
@@ -69,7 +73,13 @@ impl UserData for HpgFile {
                 .map(|u| util::gid_for_value(&u))
                 .map_or(Ok(None), |v| v.map(Some))?; // Flip Option<Result<_, _>> to Result<Option<_>, _>
 
-            util::run_chown(&this.path, uid, gid)?;
+            let changed = util::owner_differs(&this.path, uid, gid);
+            record_file_change(ctx, &this.path, changed);
+            if !check_mode(ctx) {
+                util::run_chown(&this.path, uid, gid)?;
+            } else if changed {
+                util::would_change(format!("chown {}", this.path.to_string_lossy()));
+            }
             if let Some(uid) = uid {
                 indent_output!(1, "uid: {}", uid);
             }
@@ -79,10 +89,18 @@ impl UserData for HpgFile {
 
             Ok(HpgFile::new(&this.path))
         });
-        methods.add_method("chmod", |_, this, mode: String| {
+        methods.add_method("chmod", |ctx, this, mode: String| {
             let mode = u32::from_str_radix(&mode, 8)
                 .map_err(|e| error::action_error(format!("Invalid Mode {}: {}", mode, e)))?;
             output!("Chmod {} {}", &this.path.to_string_lossy(), mode);
+            let changed = util::mode_differs(&this.path, mode);
+            record_file_change(ctx, &this.path, changed);
+            if check_mode(ctx) {
+                if changed {
+                    util::would_change(format!("chmod {:o}", mode));
+                }
+                return Ok(HpgFile::new(&this.path));
+            }
 
             let f = File::open(&this.path).map_err(error::io_error)?;
             f.set_permissions(Permissions::from_mode(mode))
@@ -90,7 +108,7 @@ impl UserData for HpgFile {
 
             Ok(HpgFile::new(&this.path))
         });
-        methods.add_method("copy", |_, this, dst: String| {
+        methods.add_method("copy", |ctx, this, dst: String| {
             let src_contents = util::read_file(&this.path).map_err(error::io_error)?;
             let cwd = Path::new(".");
             let dst = cwd.join(dst);
@@ -105,20 +123,7 @@ impl UserData for HpgFile {
                 &dst.to_string_lossy()
             );
 
-            let updated = if should_update_file(&dst, &src_contents).map_err(error::io_error)? {
-                let mut outfile = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&dst)
-                    .map_err(error::io_error)?;
-                outfile.write_all(&src_contents).map_err(error::io_error)?;
-                true
-            } else {
-                indent_output!(1, "files matched, skipped");
-                false
-            };
-            Ok(updated)
+            update_file(ctx, &dst, &src_contents)
         });
         methods.add_method(
             "template",
@@ -141,27 +146,10 @@ impl UserData for HpgFile {
                 let src_contents =
                     run_template_file(&this.path, template_context).map_err(error::task_error)?;
 
-                let updated = if should_update_file(&dst, src_contents.as_bytes())
-                    .map_err(error::io_error)?
-                {
-                    let mut outfile = OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(&dst)
-                        .map_err(error::io_error)?;
-                    outfile
-                        .write_all(src_contents.as_bytes())
-                        .map_err(error::io_error)?;
-                    true
-                } else {
-                    indent_output!(1, "files matched, skipped");
-                    false
-                };
-                Ok(updated)
+                update_file(ctx, &dst, src_contents.as_bytes())
             },
         );
-        methods.add_method("symlink", |_, this, dst: String| {
+        methods.add_method("symlink", |ctx, this, dst: String| {
             let cwd = Path::new(".");
             let dst = cwd.join(dst);
             output!(
@@ -169,6 +157,14 @@ impl UserData for HpgFile {
                 &this.path.to_string_lossy(),
                 &dst.to_string_lossy()
             );
+            let changed = std::fs::read_link(&dst).ok().as_deref() != Some(this.path.as_path());
+            record_file_change(ctx, &dst, changed);
+            if check_mode(ctx) {
+                if changed {
+                    util::would_change(format!("link {}", dst.to_string_lossy()));
+                }
+                return Ok(HpgFile::new(dst));
+            }
             if dst.exists() {
                 std::fs::remove_file(&dst).map_err(error::io_error)?;
             }
@@ -176,18 +172,26 @@ impl UserData for HpgFile {
             Ok(HpgFile::new(dst))
         });
 
-        methods.add_method("touch", |_, this, _: ()| {
+        methods.add_method("touch", |ctx, this, _: ()| {
             output!("touch {}", &this.path.to_string_lossy());
+            record_file_change(ctx, &this.path, !this.path.exists());
+            if check_mode(ctx) {
+                if !this.path.exists() {
+                    util::would_change(format!("create {}", this.path.to_string_lossy()));
+                }
+                return Ok(HpgFile::new(&this.path));
+            }
             let f = OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(&this.path)
                 .map_err(error::io_error)?;
             drop(f);
             Ok(HpgFile::new(&this.path))
         });
 
-        methods.add_method("append", |_, this, opts: Table| {
+        methods.add_method("append", |ctx, this, opts: Table| {
             output!("append to {}", &this.path.to_string_lossy());
             let src = opts.get::<Option<String>>("src")?;
             let contents = opts.get::<Option<String>>("contents")?;
@@ -212,7 +216,7 @@ impl UserData for HpgFile {
                 .get::<Option<String>>("marker")?
                 .ok_or_else(|| error::action_error("append: 'marker' is required"))?;
             let content_hash = hash::content_hash(input.as_bytes());
-            let updated = append_to_existing(&this.path, &marker, &input, &content_hash)?;
+            let updated = append_to_existing(ctx, &this.path, &marker, &input, &content_hash)?;
             Ok(updated)
         });
 
@@ -252,7 +256,7 @@ impl UserData for HpgFile {
             let input = run_template(&input, template_context)
                 .map_err(|e| error::action_error(e.to_string()))?;
             let content_hash = hash::content_hash(input.as_bytes());
-            let updated = append_to_existing(&this.path, &marker, &input, &content_hash)?;
+            let updated = append_to_existing(ctx, &this.path, &marker, &input, &content_hash)?;
             Ok(updated)
         });
         methods.add_meta_method(MetaMethod::ToString, |_, this, _: ()| {
@@ -293,7 +297,7 @@ impl UserData for HpgDir {
             Ok(exists)
         });
 
-        methods.add_method("chown", |_, this, opts: Table| {
+        methods.add_method("chown", |ctx, this, opts: Table| {
             let user: Option<mlua::Value> = opts.get("user")?;
             let group: Option<mlua::Value> = opts.get("group")?;
             let recursive: Option<bool> = opts.get("recursive")?;
@@ -305,7 +309,22 @@ impl UserData for HpgDir {
                 .map(|u| util::gid_for_value(&u))
                 .map_or(Ok(None), |v| v.map(Some))?; // Flip Option<Result<_, _>> to Result<Option<_>, _>
 
-            if recursive {
+            let changed = if recursive {
+                util::owner_differs_recursive(&this.path, uid, gid)
+            } else {
+                util::owner_differs(&this.path, uid, gid)
+            };
+            record_file_change(ctx, &this.path, changed);
+            if check_mode(ctx) {
+                output!("Chown {}:", &this.path.to_string_lossy());
+                if changed {
+                    util::would_change(format!(
+                        "chown {}{}",
+                        this.path.to_string_lossy(),
+                        if recursive { " (recursive)" } else { "" }
+                    ));
+                }
+            } else if recursive {
                 output!("Chown {} (recursive):", &this.path.to_string_lossy());
                 util::run_chown_recursive(&this.path, uid, gid)?;
             } else {
@@ -320,10 +339,18 @@ impl UserData for HpgDir {
             }
             Ok(HpgFile::new(&this.path))
         });
-        methods.add_method("chmod", |_, this, mode_str: String| {
+        methods.add_method("chmod", |ctx, this, mode_str: String| {
             let mode = u32::from_str_radix(&mode_str, 8)
                 .map_err(|e| error::action_error(format!("Invalid Mode {}: {}", mode_str, e)))?;
             output!("Chmod {} {}", &this.path.to_string_lossy(), mode_str);
+            let changed = util::mode_differs(&this.path, mode);
+            record_file_change(ctx, &this.path, changed);
+            if check_mode(ctx) {
+                if changed {
+                    util::would_change(format!("chmod {}", mode_str));
+                }
+                return Ok(HpgFile::new(&this.path));
+            }
             let f = File::open(&this.path).map_err(error::io_error)?;
             f.set_permissions(Permissions::from_mode(mode))
                 .map_err(error::io_error)?;
@@ -331,14 +358,21 @@ impl UserData for HpgDir {
             Ok(HpgFile::new(&this.path))
         });
 
-        methods.add_method("mkdir", |_, this, _: ()| {
+        methods.add_method("mkdir", |ctx, this, _: ()| {
             output!("mkdir {}", &this.path.to_string_lossy());
+            record_file_change(ctx, &this.path, !this.path.is_dir());
+            if check_mode(ctx) {
+                if !this.path.is_dir() {
+                    util::would_change(format!("create {}", this.path.to_string_lossy()));
+                }
+                return Ok(HpgDir::new(&this.path));
+            }
 
             std::fs::create_dir_all(&this.path).map_err(error::io_error)?;
             Ok(HpgDir::new(&this.path))
         });
 
-        methods.add_method("symlink", |_, this, dst: String| {
+        methods.add_method("symlink", |ctx, this, dst: String| {
             let cwd = Path::new(".");
             let dst = cwd.join(dst);
             output!(
@@ -346,6 +380,14 @@ impl UserData for HpgDir {
                 &this.path.to_string_lossy(),
                 &dst.to_string_lossy()
             );
+            let changed = std::fs::read_link(&dst).ok().as_deref() != Some(this.path.as_path());
+            record_file_change(ctx, &dst, changed);
+            if check_mode(ctx) {
+                if changed {
+                    util::would_change(format!("link {}", dst.to_string_lossy()));
+                }
+                return Ok(HpgFile::new(dst));
+            }
             if dst.exists() {
                 std::fs::remove_file(&dst).map_err(error::io_error)?;
             }
@@ -353,22 +395,24 @@ impl UserData for HpgDir {
             Ok(HpgFile::new(dst))
         });
 
-        methods.add_method("copy", |_, this, dst: String| {
+        methods.add_method("copy", |ctx, this, dst: String| {
             output!("Copy directory {} to {}", this.path.to_string_lossy(), dst);
             let last_segment = this.path.file_name().unwrap();
             let dst_path = PathBuf::from(&dst).join(last_segment);
-            copy_dir_all(&this.path, &dst_path)?;
+            let changed = copy_dir_all(ctx, &this.path, &dst_path)?;
+            record_file_change(ctx, &dst_path, changed);
 
             Ok(HpgDir::new(dst_path))
         });
 
-        methods.add_method("copy_contents", |_, this, dst: String| {
+        methods.add_method("copy_contents", |ctx, this, dst: String| {
             output!(
                 "Copy directory contents from {} to {}",
                 this.path.to_string_lossy(),
                 dst
             );
-            copy_dir_all(&this.path, &dst)?;
+            let changed = copy_dir_all(ctx, &this.path, &dst)?;
+            record_file_change(ctx, Path::new(&dst), changed);
 
             Ok(HpgDir::new(dst))
         });
@@ -387,25 +431,37 @@ impl UserData for HpgDir {
     }
 }
 
-fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result<()> {
-    std::fs::create_dir_all(&dst)?;
+/// Copy `src` into `dst` recursively, returning whether any file was (or in
+/// check mode, would be) updated.
+fn copy_dir_all(lua: &Lua, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result<bool> {
+    let check = check_mode(lua);
+    let mut changed = false;
+    if !check {
+        std::fs::create_dir_all(&dst)?;
+    }
     for entry in std::fs::read_dir(src)? {
         let entry = entry?;
         let ty = entry.file_type()?;
         if ty.is_dir() {
-            copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
+            changed |= copy_dir_all(lua, entry.path(), dst.as_ref().join(entry.file_name()))?;
         } else {
             let src_contents = util::read_file(&entry.path())?;
             let dst_file = dst.as_ref().join(entry.file_name());
-            if should_update_file(&dst_file, &src_contents)? {
+            if !should_update_file(&dst_file, &src_contents)? {
+                indent_output!(1, "{} is up-to-date.", dst_file.to_string_lossy());
+                continue;
+            }
+            show_diff(lua, &dst_file, &src_contents);
+            changed = true;
+            if check {
+                util::would_change(format!("update file {}", dst_file.to_string_lossy()));
+            } else {
                 std::fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
                 indent_output!(1, "Updating file {}", dst_file.to_string_lossy());
-            } else {
-                indent_output!(1, "{} is up-to-date.", dst_file.to_string_lossy());
             }
         }
     }
-    Ok(())
+    Ok(changed)
 }
 
 pub fn file(lua: &Lua) -> Result<(), TaskError> {
@@ -467,91 +523,131 @@ pub fn homedir(lua: &Lua) -> Result<(), TaskError> {
 }
 
 fn append_to_existing(
+    lua: &Lua,
     dst: &Path,
     marker: &str,
     content: &str,
     hash: &str,
 ) -> Result<bool, mlua::Error> {
-    let updated;
+    let Some(contents) = appended_contents(dst, marker, content, hash)? else {
+        indent_output!(1, "section matched, skipped");
+        record_file_change(lua, dst, false);
+        return Ok(false);
+    };
+    show_diff(lua, dst, contents.as_bytes());
+    record_file_change(lua, dst, true);
+    if check_mode(lua) {
+        util::would_change(format!(
+            "update section {} in {}",
+            marker,
+            dst.to_string_lossy()
+        ));
+        return Ok(true);
+    }
+    let mut outfile = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(dst)
+        .map_err(error::io_error)?;
+    outfile
+        .write_all(contents.as_bytes())
+        .map_err(error::io_error)?;
+    Ok(true)
+}
+
+/// Computes the contents of `dst` with the marked section added or replaced,
+/// or `None` if the section is already up to date.
+fn appended_contents(
+    dst: &Path,
+    marker: &str,
+    content: &str,
+    hash: &str,
+) -> Result<Option<String>, mlua::Error> {
     if !dst.exists() {
-        let contents = format!("{} {}\n{}\n{} {}\n", marker, hash, content, marker, hash);
-        let mut outfile = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(dst)
-            .map_err(error::io_error)?;
-        outfile
-            .write_all(contents.as_bytes())
-            .map_err(error::io_error)?;
-        updated = true;
-    } else {
-        let mut target_contents =
-            String::from_utf8_lossy(&util::read_file(dst).map_err(error::io_error)?).to_string();
-        if target_contents.contains(marker) {
-            // we've already got a section, check if it needs updates
-            let mut found_start = false;
-            let mut found_end = false;
-            let mut matches = false;
-            let mut new_lines = Vec::new();
-            let mut output_lines = Vec::new();
-            let marker_line = format!("{} {}", marker, hash);
-            output_lines.push(marker_line.clone());
-            output_lines.extend(content.lines().map(|s| s.to_string()));
-            output_lines.push(marker_line.clone());
-
-            for line in target_contents.lines() {
-                if line.contains(marker) && !found_start {
-                    let old_hash = line.trim_start_matches(marker).trim();
-                    found_start = true;
-                    if old_hash == hash {
-                        // break early, sections match so don't touch the file
-                        matches = true;
-                        break;
-                    } else {
-                        // sections don't match, append new section and start ignoring old section
-                        new_lines.extend_from_slice(&output_lines);
-                    }
-                } else if line.contains(marker) && found_start {
-                    found_end = true;
-                } else if found_start && !found_end {
-                    // Ignore these lines, we're between the start and end and we don't match
+        return Ok(Some(format!(
+            "{} {}\n{}\n{} {}\n",
+            marker, hash, content, marker, hash
+        )));
+    }
+    let mut target_contents =
+        String::from_utf8_lossy(&util::read_file(dst).map_err(error::io_error)?).to_string();
+    if target_contents.contains(marker) {
+        // we've already got a section, check if it needs updates
+        let mut found_start = false;
+        let mut found_end = false;
+        let mut new_lines = Vec::new();
+        let mut output_lines = Vec::new();
+        let marker_line = format!("{} {}", marker, hash);
+        output_lines.push(marker_line.clone());
+        output_lines.extend(content.lines().map(|s| s.to_string()));
+        output_lines.push(marker_line.clone());
+
+        for line in target_contents.lines() {
+            if line.contains(marker) && !found_start {
+                let old_hash = line.trim_start_matches(marker).trim();
+                found_start = true;
+                if old_hash == hash {
+                    // sections match so don't touch the file
+                    return Ok(None);
                 } else {
-                    new_lines.push(line.to_string());
+                    // sections don't match, append new section and start ignoring old section
+                    new_lines.extend_from_slice(&output_lines);
                 }
-            }
-            if !matches {
-                let mut outfile = OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .open(dst)
-                    .map_err(error::io_error)?;
-                outfile
-                    .write_all(new_lines.join("\n").as_bytes())
-                    .map_err(error::io_error)?;
-                updated = true;
+            } else if line.contains(marker) && found_start {
+                found_end = true;
+            } else if found_start && !found_end {
+                // Ignore these lines, we're between the start and end and we don't match
             } else {
-                indent_output!(1, "section matched, skipped");
-                updated = false;
+                new_lines.push(line.to_string());
             }
-        } else {
-            // just append, currently doesn't exist
-            target_contents.push_str(&format!(
-                "\n{} {}\n{}\n{} {}\n",
-                marker, hash, content, marker, hash
-            ));
-            let mut outfile = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(dst)
-                .map_err(error::io_error)?;
-            outfile
-                .write_all(target_contents.as_bytes())
-                .map_err(error::io_error)?;
-            updated = true;
         }
+        Ok(Some(new_lines.join("\n")))
+    } else {
+        // just append, currently doesn't exist
+        target_contents.push_str(&format!(
+            "\n{} {}\n{}\n{} {}\n",
+            marker, hash, content, marker, hash
+        ));
+        Ok(Some(target_contents))
     }
-    Ok(updated)
+}
+
+/// Writes `contents` to `dst` if they differ from what's there, returning
+/// whether the file was (or in check mode, would be) updated.
+fn update_file(lua: &Lua, dst: &Path, contents: &[u8]) -> Result<bool, mlua::Error> {
+    if !should_update_file(dst, contents).map_err(error::io_error)? {
+        indent_output!(1, "files matched, skipped");
+        record_file_change(lua, dst, false);
+        return Ok(false);
+    }
+    show_diff(lua, dst, contents);
+    record_file_change(lua, dst, true);
+    if check_mode(lua) {
+        util::would_change(format!("update {}", dst.to_string_lossy()));
+        return Ok(true);
+    }
+    let mut outfile = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)
+        .map_err(error::io_error)?;
+    outfile.write_all(contents).map_err(error::io_error)?;
+    Ok(true)
+}
+
+/// Print a diff of `dst` against its new contents when `--diff` is set.
+fn show_diff(lua: &Lua, dst: &Path, new: &[u8]) {
+    if !diff_mode(lua) {
+        return;
+    }
+    let old = if dst.is_file() {
+        util::read_file(dst).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    tracker::tracker().file_diff(diff::unified_diff(dst, &old, new));
 }
 
 fn should_update_file(dst: &Path, contents: &[u8]) -> Result<bool, std::io::Error> {
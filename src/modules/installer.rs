@@ -12,6 +12,7 @@ use crate::{
     actions::util,
     error::{self, TaskError},
     indent_output,
+    task::context::{check_mode, record_change},
 };
 use crate::{output, Result};
 
@@ -114,16 +115,29 @@ impl HpgInstaller {
         }
     }
 
-    fn install(&self) -> Result<HpgDir, mlua::Error> {
+    fn install(&self, lua: &Lua) -> Result<HpgDir, mlua::Error> {
+        let check = check_mode(lua);
         let f = match &self.src {
             InstallSource::Url { url, archive_path } => {
                 let dir = archive_path.parent().ok_or_else(|| {
                     error::action_error(format!("Invalid archive_path {}", &archive_path.display()))
                 })?;
-                std::fs::create_dir_all(dir).map_err(error::io_error)?;
+                if !check {
+                    std::fs::create_dir_all(dir).map_err(error::io_error)?;
+                }
                 output!("Installing {}", archive_path.display());
                 if self.hash_matches() {
                     indent_output!(1, "Hashes matched, skipped install");
+                    record_change(lua, false);
+                    return Ok(HpgDir::new(&self.extract_dir));
+                }
+                record_change(lua, true);
+                if check {
+                    util::would_change(format!(
+                        "download {} and extract to {}",
+                        url,
+                        self.extract_dir.display()
+                    ));
                     return Ok(HpgDir::new(&self.extract_dir));
                 }
                 let archive = self.download()?;
@@ -141,6 +155,12 @@ impl HpgInstaller {
 
                 if self.hash_matches() {
                     indent_output!(1, "Hashes matched, skipped install");
+                    record_change(lua, false);
+                    return Ok(HpgDir::new(&self.extract_dir));
+                }
+                record_change(lua, true);
+                if check {
+                    util::would_change(format!("extract to {}", self.extract_dir.display()));
                     return Ok(HpgDir::new(&self.extract_dir));
                 }
                 if let Some(ty) = HpgArchive::guess_archive_type(&f.to_string_lossy()) {
@@ -159,14 +179,14 @@ impl HpgInstaller {
 
 impl UserData for HpgInstaller {
     fn add_methods<T: mlua::UserDataMethods<Self>>(methods: &mut T) {
-        methods.add_method("install", |_, this, _: ()| this.install());
+        methods.add_method("install", |ctx, this, _: ()| this.install(ctx));
         methods.add_method("is_installed", |_, this, _: ()| Ok(this.hash_matches()));
     }
 }
 
 pub fn installer(lua: &Lua) -> Result<(), TaskError> {
     let f = lua.create_function(
-        |_, (archive_path, extract_dir, opts): (String, String, Table)| {
+        |ctx, (archive_path, extract_dir, opts): (String, String, Table)| {
             let url = opts.get::<Option<String>>("url")?;
             let hash = opts.get::<Option<String>>("hash")?;
             let install_dir = opts.get::<Option<String>>("install_dir")?;
@@ -190,7 +210,7 @@ pub fn installer(lua: &Lua) -> Result<(), TaskError> {
                 extract_dir,
                 install_dir,
             };
-            i.install()
+            i.install(ctx)
         },
     )?;
     lua.globals().set("install", f)?;
@@ -1,6 +1,8 @@
 use crate::{
     error::{self, TaskError},
-    output, Result,
+    output,
+    task::context::{check_mode, record_file_change},
+    Result,
 };
 use mlua::{Lua, Table, UserData};
 use reqwest::{
@@ -8,6 +10,7 @@ use reqwest::{
     Error as ReqwestError, IntoUrl, StatusCode, Url,
 };
 use std::fs::OpenOptions;
+use std::path::Path;
 
 use crate::actions::util;
 
@@ -108,6 +111,11 @@ impl UserData for HpgUrl {
             };
             let builder = this.opts_to_request(&client, &opts)?;
             output!("Download {} to  {}", &this.url, &dst);
+            record_file_change(ctx, Path::new(&dst), true);
+            if check_mode(ctx) {
+                util::would_change(format!("download to {}", &dst));
+                return Ok(HpgFile::new(&dst));
+            }
             let mut res = builder
                 .send()
                 .map_err(|e| error::action_error(format!("{}", e)))?;
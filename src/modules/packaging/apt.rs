@@ -57,6 +57,7 @@ impl AptManager {
             true,
             true,
             false,
+            None,
         )?;
         let mut statuses = Vec::new();
         for line in output.stdout.lines() {
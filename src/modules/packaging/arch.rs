@@ -48,6 +48,7 @@ impl ArchManager {
             true,
             true,
             echo,
+            None,
         )?;
 
         Ok(output)
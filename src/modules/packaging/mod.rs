@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 
+use crate::actions::util;
 use crate::error::TaskError;
 use crate::{indent_output, output, Result};
 
@@ -33,11 +34,11 @@ pub trait PackageManager {
     fn call_install(&self, packages: &[InstallRequest]) -> Result<(), TaskError>;
     fn call_remove(&self, packages: &[&str]) -> Result<(), TaskError>;
 
-    fn install_packages(
+    /// Requests from `packages` that aren't installed at the requested version.
+    fn pending_installs(
         &self,
         packages: &[InstallRequest],
-    ) -> Result<Vec<PackageStatus>, TaskError> {
-        output!("install packages:");
+    ) -> Result<Vec<InstallRequest>, TaskError> {
         let mut requests: Vec<InstallRequest> = Vec::new();
         let package_names: Vec<&str> = packages.iter().map(|r| r.name.as_str()).collect();
         let statuses = self.package_status(&package_names)?;
@@ -75,10 +76,30 @@ pub trait PackageManager {
                 requests.push(package.clone());
             }
         }
+        Ok(requests)
+    }
+
+    fn install_packages(
+        &self,
+        packages: &[InstallRequest],
+        check: bool,
+    ) -> Result<Vec<PackageStatus>, TaskError> {
+        output!("install packages:");
+        let package_names: Vec<&str> = packages.iter().map(|r| r.name.as_str()).collect();
+        let requests = self.pending_installs(packages)?;
         if requests.is_empty() {
             output!("No packages to install.");
             return Ok(Vec::new());
         }
+        if check {
+            for r in requests.iter() {
+                match &r.version {
+                    Some(Version(v)) => util::would_change(format!("install {} {}", r.name, v)),
+                    None => util::would_change(format!("install {}", r.name)),
+                }
+            }
+            return self.package_status(&package_names);
+        }
         self.call_install(&requests)?;
 
         let statuses = self.package_status(&package_names)?;
@@ -93,21 +114,43 @@ pub trait PackageManager {
         Ok(statuses)
     }
 
-    fn remove_packages(&self, packages: &[&str]) -> Result<Vec<PackageStatus>, TaskError> {
-        self.call_remove(packages)?;
+    /// Remove any of `packages` that are installed, returning whether anything
+    /// was (or in check mode, would be) removed.
+    fn remove_packages(
+        &self,
+        packages: &[&str],
+        check: bool,
+    ) -> Result<(bool, Vec<PackageStatus>), TaskError> {
+        let status = self.package_status(packages)?;
+        let installed: Vec<&str> = status
+            .iter()
+            .filter(|s| matches!(s.status, InstallStatus::Installed(_)))
+            .map(|s| s.package.as_str())
+            .collect();
+        if installed.is_empty() {
+            return Ok((false, status));
+        }
+        if check {
+            for p in installed {
+                util::would_change(format!("remove {}", p));
+            }
+            return Ok((true, status));
+        }
+        self.call_remove(&installed)?;
         let status = self.package_status(packages)?;
         for s in status.iter() {
             if let InstallStatus::Installed(_s) = &s.status {
                 return Err(TaskError::Action(format!("Failed to remove {}", s.package)));
             }
         }
-        Ok(status)
+        Ok((true, status))
     }
 
     fn ensure(
         &self,
         packages: &[InstallRequest],
         update: bool,
+        check: bool,
     ) -> Result<(bool, Vec<PackageStatus>), TaskError> {
         let package_names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
         let statuses = self.package_status(&package_names)?;
@@ -121,6 +164,15 @@ pub trait PackageManager {
         if missing.is_empty() {
             return Ok((false, statuses));
         }
+        if check {
+            for p in statuses
+                .iter()
+                .filter(|p| missing.contains(p.package.as_str()))
+            {
+                util::would_change(format!("install {}", p.package));
+            }
+            return Ok((true, statuses));
+        }
         if update {
             self.call_update_repos()?;
         }
@@ -130,6 +182,6 @@ pub trait PackageManager {
             .cloned()
             .collect();
 
-        Ok((true, self.install_packages(&missing_requests)?))
+        Ok((true, self.install_packages(&missing_requests, false)?))
     }
 }
@@ -1,6 +1,9 @@
 use crate::{
+    actions::util,
     error::{self, TaskError},
-    indent_output, output, Result,
+    indent_output, output,
+    task::context::{check_mode, record_change},
+    Result,
 };
 use mlua::{Lua, Table};
 
@@ -33,10 +36,16 @@ fn apt(ctx: &Lua) -> Result<Table, mlua::Error> {
                 .get::<bool>("_updated")?;
             !already_updated
         };
+        record_change(ctx, do_update);
         if !do_update {
             output!("update repos: skip");
             return Ok(do_update);
         }
+        if check_mode(ctx) {
+            output!("update repos:");
+            util::would_change("update package repositories");
+            return Ok(do_update);
+        }
         let apt = AptManager::new();
         apt.call_update_repos().map_err(error::task_error)?;
         ctx.globals()
@@ -60,7 +69,10 @@ fn apt(ctx: &Lua) -> Result<Table, mlua::Error> {
             .map(value_to_install_request)
             .collect::<Result<Vec<InstallRequest>, mlua::Error>>()?;
         let apt = AptManager::new();
-        let installed = apt.install_packages(&packages).map_err(error::task_error)?;
+        let installed = apt
+            .install_packages(&packages, check_mode(ctx))
+            .map_err(error::task_error)?;
+        record_change(ctx, !installed.is_empty());
         let res = installed
             .into_iter()
             .map(|i| package_status_to_lua(ctx, &i))
@@ -72,9 +84,11 @@ fn apt(ctx: &Lua) -> Result<Table, mlua::Error> {
     let remove = ctx.create_function(|ctx, packages: Vec<String>| {
         let apt = AptManager::new();
         let r: Vec<&str> = packages.iter().map(|r| r.as_ref()).collect();
-        let packages = apt
-            .remove_packages(&r)
-            .map_err(error::task_error)?
+        let (removed, statuses) = apt
+            .remove_packages(&r, check_mode(ctx))
+            .map_err(error::task_error)?;
+        record_change(ctx, removed);
+        let packages = statuses
             .into_iter()
             .map(|p| package_status_to_lua(ctx, &p))
             .collect::<Result<Vec<Table>, mlua::Error>>()?;
@@ -102,12 +116,13 @@ fn apt(ctx: &Lua) -> Result<Table, mlua::Error> {
                 .join(", ")
         );
         let (updated, statuses) = apt
-            .ensure(&packages, !already_updated)
+            .ensure(&packages, !already_updated, check_mode(ctx))
             .map_err(error::task_error)?;
+        record_change(ctx, updated);
         let res_tbl = ctx.create_table()?;
         if !updated {
             indent_output!(1, "Ensure: Packages all up-to-date.");
-        } else {
+        } else if !check_mode(ctx) {
             indent_output!(
                 1,
                 "Ensure: Installed {}",
@@ -157,10 +172,16 @@ fn arch(ctx: &Lua) -> Result<Table, mlua::Error> {
                 .get::<bool>("_updated")?;
             !already_updated
         };
+        record_change(ctx, do_update);
         if !do_update {
             output!("update repos: skip");
             return Ok(do_update);
         }
+        if check_mode(ctx) {
+            output!("update repos:");
+            util::would_change("update package repositories");
+            return Ok(do_update);
+        }
 
         let pacman = ArchManager::new(get_arch_manager(ctx));
         pacman.call_update_repos().map_err(error::task_error)?;
@@ -186,8 +207,9 @@ fn arch(ctx: &Lua) -> Result<Table, mlua::Error> {
             .collect::<Result<Vec<InstallRequest>, mlua::Error>>()?;
         let pacman = ArchManager::new(get_arch_manager(ctx));
         let installed = pacman
-            .install_packages(&packages)
+            .install_packages(&packages, check_mode(ctx))
             .map_err(error::task_error)?;
+        record_change(ctx, !installed.is_empty());
         let res = installed
             .into_iter()
             .map(|i| package_status_to_lua(ctx, &i))
@@ -199,9 +221,11 @@ fn arch(ctx: &Lua) -> Result<Table, mlua::Error> {
     let remove = ctx.create_function(|ctx, packages: Vec<String>| {
         let pacman = ArchManager::new(get_arch_manager(ctx));
         let r: Vec<&str> = packages.iter().map(|r| r.as_ref()).collect();
-        let packages = pacman
-            .remove_packages(&r)
-            .map_err(error::task_error)?
+        let (removed, statuses) = pacman
+            .remove_packages(&r, check_mode(ctx))
+            .map_err(error::task_error)?;
+        record_change(ctx, removed);
+        let packages = statuses
             .into_iter()
             .map(|p| package_status_to_lua(ctx, &p))
             .collect::<Result<Vec<Table>, mlua::Error>>()?;
@@ -229,12 +253,13 @@ fn arch(ctx: &Lua) -> Result<Table, mlua::Error> {
                 .join(", ")
         );
         let (updated, statuses) = pacman
-            .ensure(&packages, !already_updated)
+            .ensure(&packages, !already_updated, check_mode(ctx))
             .map_err(error::task_error)?;
+        record_change(ctx, updated);
         let res_tbl = ctx.create_table()?;
         if !updated {
             indent_output!(1, "Ensure: Packages all up-to-date.");
-        } else {
+        } else if !check_mode(ctx) {
             indent_output!(
                 1,
                 "Ensure: Installed {}",
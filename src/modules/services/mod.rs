@@ -1,6 +1,8 @@
 use mlua::{Lua, UserData};
 
+use crate::actions::util;
 use crate::error::{self, TaskError};
+use crate::task::context::{check_mode, record_change};
 use crate::{output, Result};
 
 use self::systemd::{JobResult, SystemdUnit};
@@ -10,26 +12,98 @@ pub struct HpgSystemdUnit {
     unit: SystemdUnit,
 }
 
+impl HpgSystemdUnit {
+    /// Record whether a job changes the unit, reporting it in check mode.
+    /// Jobs with a `target` active state are no-ops when the unit is already
+    /// in it. Returns whether the job should actually run.
+    fn prepare_job(
+        &self,
+        lua: &Lua,
+        verb: &str,
+        target: Option<&str>,
+    ) -> Result<bool, mlua::Error> {
+        let state = match target {
+            Some(_) => Some(self.unit.active_state().map_err(error::task_error)?),
+            None => None,
+        };
+        let changed = target.is_none() || target != state.as_deref();
+        record_change(lua, changed);
+        if !check_mode(lua) {
+            return Ok(true);
+        }
+        match state {
+            Some(state) if changed => util::would_change(format!(
+                "{} service {} ({})",
+                verb,
+                self.unit.service(),
+                state
+            )),
+            Some(_) => {}
+            None => util::would_change(format!("{} service {}", verb, self.unit.service())),
+        }
+        Ok(false)
+    }
+
+    /// Like `prepare_job`, for unit file changes. The change is a no-op when
+    /// the unit file is already in a state accepted by `done`.
+    fn prepare_file_change(
+        &self,
+        lua: &Lua,
+        verb: &str,
+        done: fn(&str) -> bool,
+    ) -> Result<bool, mlua::Error> {
+        let state = self.unit.unit_file_state().ok();
+        let changed = !state.as_deref().is_some_and(done);
+        record_change(lua, changed);
+        if !check_mode(lua) {
+            return Ok(true);
+        }
+        if changed {
+            match state {
+                Some(state) => util::would_change(format!(
+                    "{} service {} ({})",
+                    verb,
+                    self.unit.service(),
+                    state
+                )),
+                None => util::would_change(format!("{} service {}", verb, self.unit.service())),
+            }
+        }
+        Ok(false)
+    }
+}
+
 impl UserData for HpgSystemdUnit {
     fn add_methods<T: mlua::UserDataMethods<Self>>(methods: &mut T) {
         // Connection methods
-        methods.add_method("daemon_reload", |_, this, _: ()| {
+        methods.add_method("daemon_reload", |ctx, this, _: ()| {
             output!("Reloading systemd daemon...");
+            record_change(ctx, true);
+            if check_mode(ctx) {
+                util::would_change("reload systemd daemon");
+                return Ok(());
+            }
             this.unit.daemon_reload().map_err(error::task_error)?;
             output!("{}", "Daemon reloaded.".to_string());
             Ok(())
         });
 
         // Service Control Methods
-        methods.add_method("start", |_, this, _: ()| {
+        methods.add_method("start", |ctx, this, _: ()| {
             output!("Starting service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "start", Some("active"))? {
+                return Ok(JobResult::Done);
+            }
             let res = this.unit.start().map_err(error::task_error)?;
             output!("Starting service {}: {}", this.unit.service(), res.as_lua());
             Ok(res)
         });
 
-        methods.add_method("must_start", |_, this, _: ()| {
+        methods.add_method("must_start", |ctx, this, _: ()| {
             output!("Starting service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "start", Some("active"))? {
+                return Ok(());
+            }
             let res = this.unit.start().map_err(error::task_error)?;
             if res != JobResult::Done {
                 return Err(error::action_error(format!(
@@ -41,15 +115,21 @@ impl UserData for HpgSystemdUnit {
             Ok(())
         });
 
-        methods.add_method("stop", |_, this, _: ()| {
+        methods.add_method("stop", |ctx, this, _: ()| {
             output!("Stopping service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "stop", Some("inactive"))? {
+                return Ok(JobResult::Done);
+            }
             let res = this.unit.stop().map_err(error::task_error)?;
             output!("Stopping service {}: {}", this.unit.service(), res.as_lua());
             Ok(res)
         });
 
-        methods.add_method("must_stop", |_, this, _: ()| {
+        methods.add_method("must_stop", |ctx, this, _: ()| {
             output!("Stopping service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "stop", Some("inactive"))? {
+                return Ok(());
+            }
             let res = this.unit.start().map_err(error::task_error)?;
             if res != JobResult::Done {
                 return Err(error::action_error(format!(
@@ -61,8 +141,11 @@ impl UserData for HpgSystemdUnit {
             Ok(())
         });
 
-        methods.add_method("reload", |_, this, _: ()| {
+        methods.add_method("reload", |ctx, this, _: ()| {
             output!("Reloading service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "reload", None)? {
+                return Ok(JobResult::Done);
+            }
             let res = this.unit.reload().map_err(error::task_error)?;
             output!(
                 "Reloading service {}: {}",
@@ -72,8 +155,11 @@ impl UserData for HpgSystemdUnit {
             Ok(res)
         });
 
-        methods.add_method("must_reload", |_, this, _: ()| {
+        methods.add_method("must_reload", |ctx, this, _: ()| {
             output!("Reloading service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "reload", None)? {
+                return Ok(());
+            }
             let res = this.unit.reload().map_err(error::task_error)?;
             if res != JobResult::Done {
                 return Err(error::action_error(format!(
@@ -89,8 +175,11 @@ impl UserData for HpgSystemdUnit {
             Ok(())
         });
 
-        methods.add_method("restart", |_, this, _: ()| {
+        methods.add_method("restart", |ctx, this, _: ()| {
             output!("Restarting service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "restart", None)? {
+                return Ok(JobResult::Done);
+            }
             let res = this.unit.restart().map_err(error::task_error)?;
             output!(
                 "Restarting service {}: {}",
@@ -100,8 +189,11 @@ impl UserData for HpgSystemdUnit {
             Ok(res)
         });
 
-        methods.add_method("must_restart", |_, this, _: ()| {
+        methods.add_method("must_restart", |ctx, this, _: ()| {
             output!("Restarting service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "restart", None)? {
+                return Ok(());
+            }
             let res = this.unit.restart().map_err(error::task_error)?;
             if res != JobResult::Done {
                 return Err(error::action_error(format!(
@@ -117,8 +209,11 @@ impl UserData for HpgSystemdUnit {
             Ok(())
         });
 
-        methods.add_method("reload_or_restart", |_, this, _: ()| {
+        methods.add_method("reload_or_restart", |ctx, this, _: ()| {
             output!("Reload/restart service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "reload or restart", None)? {
+                return Ok(JobResult::Done);
+            }
             let res = this.unit.reload_or_restart().map_err(error::task_error)?;
             output!(
                 "Reload/restart service {}: {}",
@@ -128,8 +223,11 @@ impl UserData for HpgSystemdUnit {
             Ok(res)
         });
 
-        methods.add_method("must_reload_or_restart", |_, this, _: ()| {
+        methods.add_method("must_reload_or_restart", |ctx, this, _: ()| {
             output!("Reload/restart service {}...", this.unit.service());
+            if !this.prepare_job(ctx, "reload or restart", None)? {
+                return Ok(());
+            }
             let res = this.unit.reload_or_restart().map_err(error::task_error)?;
             if res != JobResult::Done {
                 return Err(error::action_error(format!(
@@ -146,44 +244,65 @@ impl UserData for HpgSystemdUnit {
         });
 
         // Service activation methods
-        methods.add_method("enable", |_, this, _: ()| {
+        methods.add_method("enable", |ctx, this, _: ()| {
             output!("Enable service {}", this.unit.service());
+            if !this.prepare_file_change(ctx, "enable", |s| s == "enabled")? {
+                return Ok(());
+            }
             this.unit.enable(false).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("force_enable", |_, this, _: ()| {
+        methods.add_method("force_enable", |ctx, this, _: ()| {
             output!("Enable service {} (forced)", this.unit.service());
+            if !this.prepare_file_change(ctx, "enable", |s| s == "enabled")? {
+                return Ok(());
+            }
             this.unit.enable(true).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("disable", |_, this, _: ()| {
+        methods.add_method("disable", |ctx, this, _: ()| {
             output!("Disable service {}", this.unit.service());
+            if !this.prepare_file_change(ctx, "disable", |s| s != "enabled")? {
+                return Ok(());
+            }
             this.unit.disable(false).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("force_disable", |_, this, _: ()| {
+        methods.add_method("force_disable", |ctx, this, _: ()| {
             output!("Disable service {} (forced)", this.unit.service());
+            if !this.prepare_file_change(ctx, "disable", |s| s != "enabled")? {
+                return Ok(());
+            }
             this.unit.disable(true).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("mask", |_, this, _: ()| {
+        methods.add_method("mask", |ctx, this, _: ()| {
             output!("Mask service {}", this.unit.service());
+            if !this.prepare_file_change(ctx, "mask", |s| s == "masked")? {
+                return Ok(());
+            }
             this.unit.mask(false).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("force_mask", |_, this, _: ()| {
+        methods.add_method("force_mask", |ctx, this, _: ()| {
             output!("Mask service {} (forced)", this.unit.service());
+            if !this.prepare_file_change(ctx, "mask", |s| s == "masked")? {
+                return Ok(());
+            }
             this.unit.mask(true).map_err(error::task_error)?;
             Ok(())
         });
 
-        methods.add_method("unmask", |_, this, _: ()| {
+        methods.add_method("unmask", |ctx, this, _: ()| {
             output!("Unmask service {}", this.unit.service());
+            if !this.prepare_file_change(ctx, "unmask", |s| s != "masked")? {
+                return Ok(());
+            }
             this.unit.unmask().map_err(error::task_error)?;
             Ok(())
         });
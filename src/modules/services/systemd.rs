@@ -31,6 +31,12 @@ trait Systemd {
     /// All of the below methods that take a unit name would also be available on that object.
     fn get_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
 
+    /// Like `get_unit`, but loads the unit from disk if it isn't loaded yet.
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+
+    /// Get the install state of a unit file, e.g. "enabled", "disabled", "masked" or "static".
+    fn get_unit_file_state(&self, file: &str) -> zbus::Result<String>;
+
     /// Tell systemd to reload unit files
     fn reload(&self) -> zbus::Result<()>;
 
@@ -102,6 +108,16 @@ trait Systemd {
     fn unit_removed(&self, id: u32, unit: ObjectPath<'_>) -> zbus::Result<()>;
 }
 
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Unit {
+    /// One of "active", "reloading", "inactive", "failed", "activating", or "deactivating".
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum JobResult {
     Done,
@@ -247,6 +263,16 @@ impl SystemdUnit {
         Ok(self.manager()?.unmask_unit_files(&[&self.unit], false)?)
     }
 
+    pub fn active_state(&self) -> Result<String> {
+        let path = self.manager()?.load_unit(&self.unit)?;
+        let unit = UnitProxyBlocking::builder(&self.conn).path(path)?.build()?;
+        Ok(unit.active_state()?)
+    }
+
+    pub fn unit_file_state(&self) -> Result<String> {
+        Ok(self.manager()?.get_unit_file_state(&self.unit)?)
+    }
+
     pub fn service(&self) -> &str {
         &self.unit
     }
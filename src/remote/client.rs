@@ -1,9 +1,9 @@
-use std::path::Path;
+use std::{collections::HashSet, path::Path};
 
 use ignore::{overrides::OverrideBuilder, WalkBuilder};
 use pathdiff::diff_paths;
 
-use crate::error::HpgRemoteError;
+use crate::{error::HpgRemoteError, secrets, task::roles::ROLES_DIR};
 
 use super::messages::{FileType, LocalFile};
 
@@ -14,6 +14,7 @@ pub fn find_hpg_files(root: &Path) -> Result<Vec<LocalFile>, HpgRemoteError> {
     let overrides = OverrideBuilder::new(root)
         .case_insensitive(true)?
         .add("!.meta/")?
+        .add("!.hpg/")?
         .add("!.hpgignore")?
         .add("!inventory.toml")?
         .build()?;
@@ -22,18 +23,47 @@ pub fn find_hpg_files(root: &Path) -> Result<Vec<LocalFile>, HpgRemoteError> {
         .overrides(overrides)
         .build()
     {
-        let f = res?;
-        let ty = if f.path().is_dir() {
-            FileType::Dir
-        } else if f.path().is_file() {
-            FileType::File
-        } else {
-            unreachable!()
-        };
-        files.push(LocalFile {
-            ty,
-            rel_path: diff_paths(f.path(), root).unwrap(),
-        });
+        files.push(local_file(res?.path(), root));
+    }
+
+    // Roles are always synced, even if something like a .gitignore would
+    // leave them out. Only .hpgignore applies.
+    let roles = root.join(ROLES_DIR);
+    if roles.is_dir() {
+        let mut seen: HashSet<_> = files.iter().map(|f| f.rel_path.clone()).collect();
+        for res in WalkBuilder::new(&roles)
+            .git_ignore(false)
+            .git_global(false)
+            .git_exclude(false)
+            .ignore(false)
+            .parents(false)
+            .add_custom_ignore_filename(".hpgignore")
+            .build()
+        {
+            let f = local_file(res?.path(), root);
+            if seen.insert(f.rel_path.clone()) {
+                files.push(f);
+            }
+        }
+    }
+
+    // The key for encrypted variables stays local, even if it's in the project
+    if let Some(key) = secrets::key_file().and_then(|k| k.canonicalize().ok()) {
+        files.retain(|f| root.join(&f.rel_path).canonicalize().ok().as_ref() != Some(&key));
     }
     Ok(files)
 }
+
+fn local_file(path: &Path, root: &Path) -> LocalFile {
+    let ty = if path.is_dir() {
+        FileType::Dir
+    } else if path.is_file() {
+        FileType::File
+    } else {
+        unreachable!()
+    };
+    LocalFile {
+        ty,
+        rel_path: diff_paths(path, root).unwrap(),
+    }
+}
@@ -2,7 +2,7 @@ use std::{collections::HashMap, fs, path::Path};
 
 use serde::Deserialize;
 
-use crate::{error::HpgRemoteError, Result};
+use crate::{error::HpgRemoteError, task::vars::MergeStrategy, Result};
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
@@ -10,6 +10,8 @@ pub struct InventoryConfig {
     pub hosts: HashMap<String, HostConfig>,
     pub vars: HashMap<String, toml::Value>,
     pub vars_files: Vec<String>,
+    /// How variables from the inventory, hosts and command line are merged.
+    pub merge: MergeStrategy,
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-use crate::{task::Variables, tracker::TrackerEvent};
+use crate::{
+    task::{graph::GraphFormat, report::RunReport, ExecOptions, Variables},
+    tracker::{StepDecision, StepPrompt, TrackerEvent},
+};
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
 pub enum FileType {
@@ -67,6 +70,9 @@ Client              Server
 -------------------------------
 Exec     --->                       Run HPG on server side
          <---       Event           Report progress back to client
+         <---     StepPrompt        With `--step`, ask whether to run the next task
+StepDecision --->                   Answer to StepPrompt: continue, skip or abort
+         <---       Report          Run report, for `--report` (omitted if nothing ran)
          <---       Finish          Report done, summary, and success/failure
 
           **
@@ -90,6 +96,10 @@ pub enum SyncServerMessage {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ExecServerMessage {
     Event(TrackerEvent),
+    StepPrompt(StepPrompt),
+    Report(RunReport),
+    /// The run couldn't be carried out, e.g. the config failed to evaluate.
+    Failed(String),
     Finish,
 }
 
@@ -100,12 +110,14 @@ pub enum HpgMessage {
     ExecClient {
         vars: Variables,
         config: String,
-        run_defaults: bool,
-        show_plan: bool,
+        options: ExecOptions,
         list_tasks: bool,
+        list_vars: bool,
+        graph: Option<GraphFormat>,
         targets: Vec<String>,
     },
     ExecServer(ExecServerMessage),
+    StepDecision(StepDecision),
     Error(String),
     Debug(String),
 }
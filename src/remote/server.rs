@@ -13,12 +13,12 @@ use super::{
 };
 use crate::{
     error::{HpgError, HpgRemoteError},
-    indent_output, load_file, output,
+    history, load_file, output,
     remote::messages::ExecServerMessage,
-    task::{LuaState, Variables},
+    secrets,
+    task::{graph::GraphFormat, report::RunReport, ExecOptions, LuaState, Variables},
     tracker::{self, Tracker},
 };
-use console::style;
 use futures_util::{SinkExt, StreamExt};
 
 use nix::unistd::{Gid, Uid};
@@ -48,6 +48,10 @@ fn running_as_sudo() -> (bool, Uid, Gid) {
     }
 }
 
+/// Printed on stdout once the server has the run lock and is listening, so
+/// the client knows the socket is this server's.
+pub const SERVER_READY: &str = "HPG-SERVER-READY";
+
 pub fn run_socket_server(
     root_dir: String,
     lua: LuaState,
@@ -83,6 +87,7 @@ async fn listen_socket(
     if let (true, uid, gid) = running_as_sudo() {
         std::os::unix::fs::chown(socket_path, Some(uid.as_raw()), Some(gid.as_raw())).unwrap();
     }
+    println!("{}", SERVER_READY);
 
     // should wait for client to connect
     let res = match time::timeout(Duration::from_secs(5), listener.accept()).await {
@@ -93,6 +98,10 @@ async fn listen_socket(
         }
     };
     let (stream, _addr) = res?;
+    // Only one client per run. Another client waiting on the lock must not
+    // connect to this run's socket.
+    drop(listener);
+    tokio::fs::remove_file(&socket_path).await?;
     let mut rw = Framed::new(stream, HpgCodec::<HpgMessage>::new());
     server_sync(root_dir, &mut rw).await?;
 
@@ -168,26 +177,32 @@ async fn server_exec_hpg(
         HpgMessage::ExecClient {
             vars,
             config,
-            run_defaults,
-            show_plan,
+            options,
             list_tasks,
+            list_vars,
+            graph,
             targets,
         } => {
             tracker::sink().to_remote(rw);
-            if let Err(e) = execute_hpg(
-                lua,
-                config,
-                vars,
-                run_defaults,
-                show_plan,
-                list_tasks,
-                targets,
-            )
-            .await
-            {
-                output!("Remote error: {}", e);
-            }
+            let listing = Listing {
+                tasks: list_tasks,
+                vars: list_vars,
+                graph,
+            };
+            let result = execute_hpg(lua, config, vars, options, listing, targets).await;
             rw = tracker::sink().to_local().unwrap();
+            match result {
+                Ok(Some(report)) => {
+                    rw.send(HpgMessage::ExecServer(ExecServerMessage::Report(report)))
+                        .await?;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let msg = secrets::redact(e.to_string());
+                    rw.send(HpgMessage::ExecServer(ExecServerMessage::Failed(msg)))
+                        .await?;
+                }
+            }
         }
         _ => unreachable!(),
     }
@@ -221,33 +236,52 @@ async fn wait_for_exec_msg(
     }
 }
 
+/// What to show instead of running tasks, if anything.
+struct Listing {
+    tasks: bool,
+    vars: bool,
+    graph: Option<GraphFormat>,
+}
+
 async fn execute_hpg(
     lua: LuaState,
     config: String,
     vars: Variables,
-    run_defaults: bool,
-    show_plan: bool,
-    list_tasks: bool,
+    options: ExecOptions,
+    listing: Listing,
     targets: Vec<String>,
-) -> Result<(), HpgRemoteError> {
+) -> Result<Option<RunReport>, HpgRemoteError> {
     tracker::tracker().run(5);
     tokio::time::sleep(Duration::from_secs(1)).await;
     output!("Config: {}, Targets: {:?}", config, targets);
     tokio::time::sleep(Duration::from_secs(1)).await;
     let code = load_file(&config).map_err(Box::new)?;
+    let inputs = history::RunInputs::new(&config, &code, &vars).map_err(Box::new)?;
 
     let lua = lua.eval(&code, vars).map_err(Box::new)?;
-    if list_tasks {
-        output!("{}", style("Available Tasks").cyan());
-        for (name, task) in lua.available_targets() {
-            indent_output!(1, "{}: {}", style(name).green(), task.description());
-        }
-        return Ok(());
+    if listing.tasks {
+        lua.list_targets();
+        return Ok(None);
+    }
+    if listing.vars {
+        lua.list_vars().map_err(|e| Box::new(HpgError::from(e)))?;
+        return Ok(None);
     }
     let requested_tasks: Vec<&str> = targets.iter().map(|t| t.as_str()).collect();
-    lua.execute(&requested_tasks, run_defaults, show_plan)
+    if let Some(format) = listing.graph {
+        let graph = lua
+            .graph(&requested_tasks, &options, format)
+            .map_err(|e| Box::new(HpgError::from(e)))?;
+        output!("{}", graph.trim_end());
+        return Ok(None);
+    }
+    let report = lua
+        .execute(&requested_tasks, &options)
         .map_err(|e| Box::new(HpgError::from(e)))?;
-    Ok(())
+    if let Some(report) = &report {
+        history::record(inputs, report);
+    }
+    Ok(report)
 }
 
 fn apply_patch(path: &Path, patch: &[u8]) -> Result<(), HpgRemoteError> {
@@ -4,6 +4,7 @@ use super::{
     messages::{
         ExecServerMessage, FileInfo, FilePatch, PatchType, SyncClientMessage, SyncServerMessage,
     },
+    server,
 };
 use crate::{
     debug_output,
@@ -13,8 +14,8 @@ use crate::{
         comms::SyncBus,
         messages::{FileStatus, HpgMessage},
     },
-    task::Variables,
-    tracker::{self, Tracker, TrackerEvent},
+    task::{vars::MergeStrategy, Variables},
+    tracker::{self, StepDecision, Tracker, TrackerEvent},
     HpgOpt,
 };
 use bytes::{BufMut, BytesMut};
@@ -34,7 +35,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::{fs::File, io::AsyncReadExt, time::timeout};
+use tokio::{fs::File, io::AsyncReadExt, sync::oneshot, time::timeout};
 use tokio_util::codec::{Decoder, LinesCodec};
 
 #[derive(Debug, Clone)]
@@ -66,32 +67,38 @@ impl Handler for Client {
  *   - variable files on the command line
  *   - variables on the command line
  */
-fn merge_vars(
+pub(crate) fn merge_vars(
     cmdline_vars: Variables,
     host_config: &Option<&HostConfig>,
     inventory: &InventoryConfig,
+    strategy: MergeStrategy,
 ) -> Result<Variables, HpgRemoteError> {
     let mut vars = Variables::default();
     for f in inventory.vars_files.iter() {
-        vars = vars.merge(Variables::from_file(f)?)?;
+        let file_vars = Variables::from_file(f)?.with_source(&format!("inventory file {}", f));
+        vars = vars.merge(file_vars, strategy)?;
     }
-    vars = vars.merge(Variables::from_toml_map(&inventory.vars)?)?;
+    let inline = Variables::from_toml_map(&inventory.vars)?.with_source("inventory vars");
+    vars = vars.merge(inline, strategy)?;
     if let Some(v) = host_config {
         for f in v.vars_files.iter() {
-            vars = vars.merge(Variables::from_file(f)?)?;
+            let file_vars = Variables::from_file(f)?.with_source(&format!("host file {}", f));
+            vars = vars.merge(file_vars, strategy)?;
         }
-        vars = vars.merge(Variables::from_toml_map(&v.vars)?)?;
+        let inline = Variables::from_toml_map(&v.vars)?.with_source("host vars");
+        vars = vars.merge(inline, strategy)?;
     }
-    vars = vars.merge(cmdline_vars)?;
+    vars = vars.merge(cmdline_vars, strategy)?;
     Ok(vars)
 }
 
+/// Run HPG on a remote host, returning whether every task succeeded.
 pub fn run_hpg_ssh(
     host: HostInfo,
     opt: HpgOpt,
     vars: Variables,
     inventory: InventoryConfig,
-) -> Result<(), HpgRemoteError> {
+) -> Result<bool, HpgRemoteError> {
     let host_config = inventory.config_for_host(&host.hostname);
     let host = if let Some(c) = host_config {
         HostInfo {
@@ -120,7 +127,8 @@ pub fn run_hpg_ssh(
         .and_then(|hc| hc.remote_exe.clone())
         .unwrap_or_else(|| "hpg".to_string());
 
-    let vars = merge_vars(vars, &host_config, &inventory)?;
+    let strategy = opt.var_opts.merge_strategy(inventory.merge);
+    let vars = merge_vars(vars, &host_config, &inventory, strategy)?;
     debug_output!("vars: {:?}", vars);
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -129,13 +137,45 @@ pub fn run_hpg_ssh(
     runtime.block_on(async move {
         let ssh_config = load_ssh_config(host, None, None)?;
         let client = Session::connect(ssh_config).await?;
-        let process = client.start_remote(&remote_path, &remote_exe, sudo).await?;
-        let socket = client.connect_socket(&root_dir, "/tmp/hpg.socket".to_string(), opt, vars);
-        let handle = tokio::spawn(process);
-        socket.await?;
-        handle.await.unwrap()?;
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let process = client
+            .start_remote(&remote_path, &remote_exe, sudo, opt.wait_lock, ready_tx)
+            .await?;
+        let socket = client.connect_socket(
+            &root_dir,
+            "/tmp/hpg.socket".to_string(),
+            ready_rx,
+            opt,
+            vars,
+        );
+        tokio::pin!(socket);
+        let mut handle = tokio::spawn(process);
+        // If the server exits first, e.g. because another run holds the lock,
+        // its error is more useful than waiting for the socket to time out
+        let succeeded = tokio::select! {
+            res = &mut socket => {
+                let succeeded = match res {
+                    Ok(s) => s,
+                    Err(e) => {
+                        // The server may have exited with a better reason
+                        if let Ok(Ok(Err(server_err))) =
+                            timeout(Duration::from_secs(1), &mut handle).await
+                        {
+                            return Err(server_err);
+                        }
+                        return Err(e);
+                    }
+                };
+                handle.await.unwrap()?;
+                succeeded
+            }
+            res = &mut handle => {
+                res.unwrap()?;
+                socket.await?
+            }
+        };
         client.close().await?;
-        Ok(())
+        Ok(succeeded)
     })
 }
 
@@ -190,32 +230,53 @@ impl Session {
         remote_path: &str,
         exe_path: &str,
         sudo: bool,
+        wait_lock: bool,
+        ready: oneshot::Sender<()>,
     ) -> Result<impl Future<Output = Result<(), HpgRemoteError>>, HpgRemoteError> {
         let mut channel = self.session.channel_open_session().await?;
         let sudo_str = if sudo { "sudo " } else { "" };
-        let cmdline = format!("{}{} server {}", sudo_str, exe_path, remote_path);
+        let wait_str = if wait_lock { " --wait-lock" } else { "" };
+        let cmdline = format!(
+            "{}{} server {}{}",
+            sudo_str, exe_path, remote_path, wait_str
+        );
         debug_output!("Remote cmdline: {}", cmdline);
         channel.exec(true, cmdline).await?;
         let block = async move {
+            let mut ready = Some(ready);
             let mut codec = LinesCodec::new();
             let mut stdout_buf = BytesMut::new();
             let mut stderr_buf = BytesMut::new();
+            let mut last_error = None;
             loop {
                 match channel.wait().await {
                     Some(ChannelMsg::Data { ref data }) => {
                         stdout_buf.put(&**data);
                         while let Some(line) = codec.decode(&mut stdout_buf).unwrap() {
                             debug_output!("S: {}", line);
+                            if line == server::SERVER_READY {
+                                if let Some(r) = ready.take() {
+                                    let _ = r.send(());
+                                }
+                            }
                         }
                     }
                     Some(ChannelMsg::ExitStatus { exit_status }) => {
                         debug_output!("Remote process exited: {}", exit_status);
+                        if exit_status != 0 {
+                            return Err(HpgRemoteError::ServerFailed(last_error.unwrap_or_else(
+                                || format!("exited with status {}", exit_status),
+                            )));
+                        }
                         break;
                     }
                     Some(ChannelMsg::ExtendedData { ref data, ext: _ }) => {
                         stderr_buf.put(&**data);
                         while let Some(line) = codec.decode(&mut stderr_buf).unwrap() {
                             debug_output!("E: {}", line);
+                            if !line.trim().is_empty() {
+                                last_error = Some(line);
+                            }
                         }
                     }
                     other => {
@@ -232,9 +293,31 @@ impl Session {
         &self,
         root_path: &Path,
         socket_path: String,
+        ready: oneshot::Receiver<()>,
         opts: HpgOpt,
         vars: Variables,
-    ) -> Result<(), HpgRemoteError> {
+    ) -> Result<bool, HpgRemoteError> {
+        // The socket path is the same for every run, so it can only be trusted
+        // once this run's server says it's listening. With --wait-lock, that's
+        // once it has the lock.
+        let ready = if opts.wait_lock {
+            Ok(ready.await)
+        } else {
+            timeout(Duration::from_secs(5), ready).await
+        };
+        match ready {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => {
+                return Err(HpgRemoteError::Unknown(
+                    "Server exited before it was ready".into(),
+                ))
+            }
+            Err(_) => {
+                return Err(HpgRemoteError::Unknown(
+                    "Timed out waiting for server".into(),
+                ))
+            }
+        }
         let mut channel =
             match timeout(Duration::from_secs(5), self.wait_for_socket(socket_path)).await {
                 Ok(c) => c?,
@@ -245,9 +328,9 @@ impl Session {
                 }
             };
         sync_files(&mut channel, root_path).await?;
-        exec_hpg(&mut channel, opts, vars).await?;
+        let succeeded = exec_hpg(&mut channel, opts, vars).await?;
         channel.eof().await?;
-        Ok(())
+        Ok(succeeded)
     }
 
     pub async fn close(&self) -> Result<(), HpgRemoteError> {
@@ -262,18 +345,21 @@ async fn exec_hpg(
     channel: &mut Channel<Msg>,
     opts: HpgOpt,
     vars: Variables,
-) -> Result<(), HpgRemoteError> {
+) -> Result<bool, HpgRemoteError> {
     let writer = channel.make_writer();
     let reader = channel.make_reader();
     let bus = SyncBus::new(reader, writer);
     let bus = bus.pin();
+    let report_path = opts.report.clone();
+    let mut succeeded = true;
 
     let msg = HpgMessage::ExecClient {
         vars,
+        options: opts.exec_options(),
         config: opts.config,
-        run_defaults: opts.run_defaults,
-        show_plan: opts.show,
         list_tasks: opts.list,
+        list_vars: opts.list_vars,
+        graph: opts.graph,
         targets: opts.targets,
     };
     bus.tx(msg).await?;
@@ -283,11 +369,15 @@ async fn exec_hpg(
             Some(HpgMessage::ExecServer(ExecServerMessage::Event(e))) => match e {
                 TrackerEvent::TaskStart(t) => tracker::tracker().task(t),
                 TrackerEvent::BatchStart(b) => tracker::tracker().run(b),
-                TrackerEvent::TaskComplete => tracker::tracker().task_success(),
-                TrackerEvent::TaskSkip => tracker::tracker().task_skip(),
-                TrackerEvent::TaskFail => tracker::tracker().task_fail(),
-                TrackerEvent::BatchSuccess => tracker::tracker().finish_success(),
-                TrackerEvent::BatchFail => tracker::tracker().finish_fail(),
+                TrackerEvent::TaskComplete(t) => tracker::tracker().task_success(t),
+                TrackerEvent::TaskSkip(t) => tracker::tracker().task_skip(t),
+                TrackerEvent::TaskFail(t) => tracker::tracker().task_fail(t),
+                TrackerEvent::FileDiff(d) => tracker::tracker().file_diff(d),
+                TrackerEvent::BatchSuccess(r) => tracker::tracker().finish_success(r),
+                TrackerEvent::BatchFail(r) => {
+                    succeeded = false;
+                    tracker::tracker().finish_fail(r)
+                }
                 TrackerEvent::Println { msg, indent } => {
                     if let Some(i) = indent {
                         tracker::tracker().indent_println(i, format_args!("{}", msg));
@@ -303,6 +393,23 @@ async fn exec_hpg(
                 TrackerEvent::SuspendBars => tracker::tracker().suspend_bars(),
                 TrackerEvent::ResumeBars => tracker::tracker().resume_bars(),
             },
+            Some(HpgMessage::ExecServer(ExecServerMessage::StepPrompt(prompt))) => {
+                let decision = tokio::task::spawn_blocking(move || tracker::prompt_step(&prompt))
+                    .await
+                    .unwrap_or(StepDecision::Abort);
+                bus.tx(HpgMessage::StepDecision(decision)).await?;
+            }
+            Some(HpgMessage::ExecServer(ExecServerMessage::Report(report))) => {
+                if let Some(path) = &report_path {
+                    report
+                        .save(path)
+                        .map_err(|e| HpgRemoteError::ExecError(Box::new(e.into())))?;
+                }
+            }
+            Some(HpgMessage::ExecServer(ExecServerMessage::Failed(e))) => {
+                succeeded = false;
+                output!("Remote error: {}", e);
+            }
             Some(HpgMessage::ExecServer(ExecServerMessage::Finish)) => break,
             Some(_) => {
                 return Err(HpgRemoteError::Unknown(
@@ -313,7 +420,7 @@ async fn exec_hpg(
         }
     }
 
-    Ok(())
+    Ok(succeeded)
 }
 
 async fn sync_files(channel: &mut Channel<Msg>, root_path: &Path) -> Result<(), HpgRemoteError> {
@@ -0,0 +1,462 @@
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, RwLock},
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Context};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use console::Term;
+use lazy_static::lazy_static;
+
+/// Prefix of an encrypted value in a variables file or inventory.
+const VALUE_PREFIX: &str = "hpg-secret:v1:";
+/// First line of a whole encrypted file.
+const FILE_HEADER: &str = "$HPG-SECRET;v1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// What secret values are replaced with in output.
+const REDACTED: &str = "********";
+/// Shorter secrets aren't redacted, they'd match too much unrelated output.
+const MIN_REDACT_LEN: usize = 4;
+
+lazy_static! {
+    static ref KEYRING: Mutex<Keyring> = Mutex::new(Keyring::default());
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+/// Where the key comes from, and the keys derived from it so far.
+#[derive(Default)]
+struct Keyring {
+    key_file: Option<PathBuf>,
+    passphrase: Option<String>,
+    /// Derived keys by salt, since deriving a key is slow on purpose.
+    keys: HashMap<[u8; SALT_LEN], [u8; 32]>,
+}
+
+impl Keyring {
+    /// The passphrase, from the key file, `$HPG_SECRET_PASSPHRASE` or a prompt.
+    /// A new passphrase typed at the prompt has to be entered twice if `confirm`.
+    fn passphrase(&mut self, confirm: bool) -> anyhow::Result<String> {
+        if let Some(p) = &self.passphrase {
+            return Ok(p.clone());
+        }
+        let passphrase = if let Some(path) = &self.key_file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read key file {}", path.to_string_lossy()))?;
+            contents.trim_end_matches(['\r', '\n']).to_string()
+        } else if let Ok(p) = std::env::var("HPG_SECRET_PASSPHRASE") {
+            p
+        } else {
+            prompt_passphrase(confirm)?
+        };
+        if passphrase.is_empty() {
+            bail!("Secret passphrase is empty");
+        }
+        self.passphrase = Some(passphrase.clone());
+        Ok(passphrase)
+    }
+
+    fn key(&mut self, salt: [u8; SALT_LEN], confirm: bool) -> anyhow::Result<[u8; 32]> {
+        if let Some(key) = self.keys.get(&salt) {
+            return Ok(*key);
+        }
+        let passphrase = self.passphrase(confirm)?;
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| anyhow!("Couldn't derive secret key: {}", e))?;
+        self.keys.insert(salt, key);
+        Ok(key)
+    }
+}
+
+fn prompt_passphrase(confirm: bool) -> anyhow::Result<String> {
+    let term = Term::stderr();
+    if !term.is_term() {
+        bail!("No key for secrets given, pass a key file or set $HPG_SECRET_KEY_FILE or $HPG_SECRET_PASSPHRASE");
+    }
+    term.write_str("Secret passphrase: ")?;
+    let passphrase = term.read_secure_line()?;
+    if confirm {
+        term.write_str("Confirm passphrase: ")?;
+        if term.read_secure_line()? != passphrase {
+            bail!("Passphrases don't match");
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Use the key in `path` for secrets, or `$HPG_SECRET_KEY_FILE` if not given.
+pub fn set_key_file(path: Option<PathBuf>) {
+    let path = path.or_else(|| std::env::var_os("HPG_SECRET_KEY_FILE").map(PathBuf::from));
+    let mut keyring = KEYRING.lock().unwrap();
+    if keyring.key_file != path {
+        *keyring = Keyring {
+            key_file: path,
+            ..Default::default()
+        };
+    }
+}
+
+/// The key file in use, if any. It's never synced to remote hosts.
+pub fn key_file() -> Option<PathBuf> {
+    KEYRING.lock().unwrap().key_file.clone()
+}
+
+/// Encrypt with AES-256-GCM, under a key derived from the passphrase with
+/// Argon2id and a random salt. Returns the salt, nonce and ciphertext.
+fn encrypt(plaintext: &[u8], confirm: bool) -> anyhow::Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = KEYRING.lock().unwrap().key(salt, confirm)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Couldn't encrypt secret"))?;
+    let mut data = salt.to_vec();
+    data.extend_from_slice(&nonce);
+    data.extend(ciphertext);
+    Ok(data)
+}
+
+fn decrypt(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < SALT_LEN + NONCE_LEN {
+        bail!("Secret is truncated");
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key = KEYRING
+        .lock()
+        .unwrap()
+        .key(salt.try_into().expect("salt length"), false)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Couldn't decrypt secret, the key is wrong or it was changed"))
+}
+
+/// Encrypt a value for use in a variables file or inventory.
+pub fn encrypt_value(value: &str) -> anyhow::Result<String> {
+    let data = encrypt(value.as_bytes(), true)?;
+    Ok(format!("{}{}", VALUE_PREFIX, STANDARD.encode(data)))
+}
+
+fn decrypt_value(value: &str) -> anyhow::Result<Option<String>> {
+    let Some(encoded) = value.strip_prefix(VALUE_PREFIX) else {
+        return Ok(None);
+    };
+    let data = STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| anyhow!("Invalid secret: {}", e))?;
+    let plaintext =
+        String::from_utf8(decrypt(&data)?).map_err(|_| anyhow!("Secret isn't valid UTF-8"))?;
+    Ok(Some(plaintext))
+}
+
+pub fn is_encrypted_file(contents: &str) -> bool {
+    contents.starts_with(FILE_HEADER)
+}
+
+fn encrypt_file_contents(plaintext: &str, confirm: bool) -> anyhow::Result<String> {
+    let encoded = STANDARD.encode(encrypt(plaintext.as_bytes(), confirm)?);
+    let mut out = format!("{}\n", FILE_HEADER);
+    for line in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Decrypt the contents of a file written by `hpg secret encrypt --file`.
+pub fn decrypt_file_contents(contents: &str) -> anyhow::Result<String> {
+    let encoded: String = contents
+        .strip_prefix(FILE_HEADER)
+        .ok_or_else(|| anyhow!("Not an encrypted file"))?
+        .split_whitespace()
+        .collect();
+    let data = STANDARD
+        .decode(encoded)
+        .map_err(|e| anyhow!("Invalid encrypted file: {}", e))?;
+    String::from_utf8(decrypt(&data)?).map_err(|_| anyhow!("Encrypted file isn't valid UTF-8"))
+}
+
+/// Decrypt every encrypted string in `json` in place, adding the plaintext
+/// values to `found`.
+pub fn decrypt_values(json: &mut serde_json::Value, found: &mut Vec<String>) -> anyhow::Result<()> {
+    match json {
+        serde_json::Value::String(s) => {
+            if let Some(plaintext) = decrypt_value(s)? {
+                found.push(plaintext.clone());
+                *s = plaintext;
+            }
+        }
+        serde_json::Value::Array(a) => {
+            for v in a.iter_mut() {
+                decrypt_values(v, found)?;
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for v in o.values_mut() {
+                decrypt_values(v, found)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Redact these values from all output from now on.
+pub fn register(secrets: &[String]) {
+    let mut registered = SECRETS.write().unwrap();
+    for s in secrets {
+        if s.len() >= MIN_REDACT_LEN && !registered.contains(s) {
+            registered.push(s.clone());
+        }
+    }
+    // Longest first, so a secret containing another is redacted whole
+    registered.sort_by_key(|s| std::cmp::Reverse(s.len()));
+}
+
+/// Replace every secret value in `s`.
+pub fn redact(s: String) -> String {
+    let secrets = SECRETS.read().unwrap();
+    if !secrets.iter().any(|secret| s.contains(secret.as_str())) {
+        return s;
+    }
+    secrets
+        .iter()
+        .fold(s, |s, secret| s.replace(secret.as_str(), REDACTED))
+}
+
+/// Replace every secret value in the strings and keys of `json`.
+pub fn redact_json(json: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match json {
+        Value::String(s) => Value::String(redact(s)),
+        Value::Array(a) => Value::Array(a.into_iter().map(redact_json).collect()),
+        Value::Object(o) => Value::Object(
+            o.into_iter()
+                .map(|(k, v)| (redact(k), redact_json(v)))
+                .collect(),
+        ),
+        v => v,
+    }
+}
+
+/// A value given on the command line, or read from stdin. Prompted for
+/// without echoing when stdin is a terminal.
+fn value_or_stdin(value: Option<String>, prompt: &str) -> anyhow::Result<String> {
+    if let Some(v) = value {
+        return Ok(v);
+    }
+    if std::io::stdin().is_terminal() {
+        let term = Term::stderr();
+        term.write_str(prompt)?;
+        return Ok(term.read_secure_line()?);
+    }
+    let mut s = String::new();
+    std::io::stdin().read_to_string(&mut s)?;
+    Ok(s.strip_suffix('\n').unwrap_or(&s).to_string())
+}
+
+/// `hpg secret encrypt`: print an encrypted value, or encrypt a file in place.
+pub fn encrypt_command(value: Option<String>, file: Option<PathBuf>) -> crate::Result<()> {
+    match file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)?;
+            if is_encrypted_file(&contents) {
+                return Err(anyhow!("{} is already encrypted", path.to_string_lossy()).into());
+            }
+            std::fs::write(&path, encrypt_file_contents(&contents, true)?)?;
+        }
+        None => {
+            let value = value_or_stdin(value, "Value to encrypt: ")?;
+            println!("{}", encrypt_value(&value)?);
+        }
+    }
+    Ok(())
+}
+
+/// `hpg secret decrypt`: print a decrypted value or file.
+pub fn decrypt_command(value: Option<String>, file: Option<PathBuf>) -> crate::Result<()> {
+    match file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)?;
+            print!("{}", decrypt_file_contents(&contents)?);
+        }
+        None => {
+            let value = value_or_stdin(value, "Value to decrypt: ")?;
+            let plaintext = decrypt_value(value.trim())?
+                .ok_or_else(|| anyhow!("Not an encrypted value, expected {}...", VALUE_PREFIX))?;
+            println!("{}", plaintext);
+        }
+    }
+    Ok(())
+}
+
+/// `hpg secret edit`: decrypt a file into a temporary file, open it in the
+/// editor, and encrypt the result back into place.
+pub fn edit_command(path: &Path) -> crate::Result<()> {
+    let existing = if path.exists() {
+        let contents = std::fs::read_to_string(path)?;
+        if !is_encrypted_file(&contents) {
+            return Err(anyhow!(
+                "{} isn't encrypted, encrypt it with `hpg secret encrypt --file` first",
+                path.to_string_lossy()
+            )
+            .into());
+        }
+        Some(decrypt_file_contents(&contents)?)
+    } else {
+        None
+    };
+    // Keep the extension so the editor highlights the file, and the
+    // plaintext in memory when possible
+    let suffix = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".hpg-secret-").suffix(&suffix);
+    let mut tmp = if Path::new("/dev/shm").is_dir() {
+        builder.tempfile_in("/dev/shm")?
+    } else {
+        builder.tempfile()?
+    };
+    tmp.write_all(existing.as_deref().unwrap_or_default().as_bytes())?;
+    tmp.flush()?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(tmp.path())
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("Editor exited with {}, file left unchanged", status).into());
+    }
+    let edited = std::fs::read_to_string(tmp.path())?;
+    if existing.as_deref() == Some(edited.as_str()) {
+        eprintln!("No changes");
+        return Ok(());
+    }
+    std::fs::write(path, encrypt_file_contents(&edited, existing.is_none())?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The keyring is shared, so tests that use it take turns.
+    static KEYRING_USERS: Mutex<()> = Mutex::new(());
+
+    fn use_passphrase(passphrase: &str) {
+        *KEYRING.lock().unwrap() = Keyring {
+            passphrase: Some(passphrase.to_string()),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let _guard = KEYRING_USERS.lock().unwrap();
+        use_passphrase("correct horse");
+        let encrypted = encrypt_value("hunter22").unwrap();
+        assert!(encrypted.starts_with(VALUE_PREFIX));
+        assert!(!encrypted.contains("hunter22"));
+        assert_eq!(
+            decrypt_value(&encrypted).unwrap().as_deref(),
+            Some("hunter22")
+        );
+        assert_eq!(decrypt_value("plain").unwrap(), None);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let _guard = KEYRING_USERS.lock().unwrap();
+        use_passphrase("correct horse");
+        let encrypted = encrypt_file_contents("password = \"hunter22\"\n", false).unwrap();
+        assert!(is_encrypted_file(&encrypted));
+        assert_eq!(
+            decrypt_file_contents(&encrypted).unwrap(),
+            "password = \"hunter22\"\n"
+        );
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let _guard = KEYRING_USERS.lock().unwrap();
+        use_passphrase("correct horse");
+        let encrypted = encrypt_value("hunter22").unwrap();
+        use_passphrase("battery staple");
+        let err = decrypt_value(&encrypted).unwrap_err();
+        assert!(err.to_string().contains("the key is wrong"));
+    }
+
+    #[test]
+    fn decrypts_nested_values() {
+        let _guard = KEYRING_USERS.lock().unwrap();
+        use_passphrase("correct horse");
+        let mut json = serde_json::json!({
+            "db": { "password": encrypt_value("hunter22").unwrap(), "port": 5432 },
+            "keys": [encrypt_value("s3cr3t-key").unwrap(), "public"],
+        });
+        let mut found = Vec::new();
+        decrypt_values(&mut json, &mut found).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "db": { "password": "hunter22", "port": 5432 },
+                "keys": ["s3cr3t-key", "public"],
+            })
+        );
+        found.sort();
+        assert_eq!(found, vec!["hunter22", "s3cr3t-key"]);
+    }
+
+    #[test]
+    fn redacts_registered_values() {
+        register(&["redact-me-1".to_string(), "redact-me-1-longer".to_string()]);
+        assert_eq!(
+            redact("a redact-me-1-longer b redact-me-1".to_string()),
+            format!("a {} b {}", REDACTED, REDACTED)
+        );
+        assert_eq!(redact("nothing here".to_string()), "nothing here");
+    }
+
+    #[test]
+    fn short_values_arent_redacted() {
+        register(&["abc".to_string()]);
+        assert_eq!(redact("abc".to_string()), "abc");
+    }
+
+    #[test]
+    fn redacts_json_strings_and_keys() {
+        register(&["redact-me-2".to_string()]);
+        let json = serde_json::json!({
+            "redact-me-2": ["x redact-me-2", 1],
+            "nested": { "value": "redact-me-2" },
+        });
+        assert_eq!(
+            redact_json(json),
+            serde_json::json!({
+                REDACTED: [format!("x {}", REDACTED), 1],
+                "nested": { "value": REDACTED },
+            })
+        );
+    }
+}
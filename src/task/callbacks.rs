@@ -0,0 +1,50 @@
+use console::style;
+use mlua::{Function, Lua, Table};
+
+use crate::{actions::util, error::TaskError, output};
+
+use super::report::RunReport;
+
+/// Registration functions and the runs they're called after.
+const CALLBACKS: &[(&str, &str)] = &[
+    ("on_success", "success"),
+    ("on_failure", "failure"),
+    ("on_finish", "finish"),
+];
+
+/// Define `on_success()`, `on_failure()` and `on_finish()`, which register
+/// functions to call with the run report once all tasks and handlers are done.
+pub(crate) fn define_callback_functions(lua: &Lua) -> Result<(), TaskError> {
+    let callbacks = lua.create_table()?;
+    for (fn_name, when) in CALLBACKS {
+        callbacks.set(*when, lua.create_table()?)?;
+        let f = lua.create_function(move |ctx, f: Function| {
+            let callbacks: Table = ctx.named_registry_value("run_callbacks")?;
+            callbacks.get::<Table>(*when)?.push(f)
+        })?;
+        lua.globals().set(*fn_name, f)?;
+    }
+    lua.set_named_registry_value("run_callbacks", callbacks)?;
+    Ok(())
+}
+
+/// Call the callbacks registered for this run's result, then the `on_finish`
+/// callbacks, in registration order. A callback that fails is reported and
+/// doesn't stop the others.
+pub(crate) fn run_callbacks(lua: &Lua, report: &RunReport) -> Result<(), TaskError> {
+    let callbacks: Table = lua.named_registry_value("run_callbacks")?;
+    let result = if report.success { "success" } else { "failure" };
+    let json = serde_json::to_value(report).map_err(|e| TaskError::Action(e.to_string()))?;
+    for when in [result, "finish"] {
+        for f in callbacks.get::<Table>(when)?.sequence_values::<Function>() {
+            let report = util::json_to_lua_value(lua, &json)?;
+            if let Err(e) = f?.call::<()>(report) {
+                output!(
+                    "{}",
+                    style(format!("Error in on_{} callback: {}", when, e)).red()
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::{
+    cell::{Cell, RefCell},
+    path::Path,
+    time::Instant,
+};
+
+use mlua::Lua;
+use serde::{Deserialize, Serialize};
+
+/// Per-run settings that actions consult while a task body executes.
+/// Stored as app data on each Lua state that runs tasks.
+#[derive(Debug, Clone, Default)]
+pub struct RunContext {
+    /// Report what would change instead of changing anything.
+    pub check: bool,
+    /// Print a diff for every file that is changed.
+    pub diff: bool,
+    /// Actions reported by the currently running task.
+    changes: Cell<ChangeCount>,
+    /// Files and directories changed by the currently running task.
+    files: RefCell<Vec<String>>,
+    /// Handlers notified by any task since the last flush, whether that's
+    /// `flush_handlers()` or the end of the run, in notification order.
+    notified: RefCell<Vec<String>>,
+    /// When the running task times out, if it has a timeout.
+    deadline: Cell<Option<Instant>>,
+}
+
+/// Number of actions in a task that changed the system, and that found it
+/// already in the desired state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeCount {
+    pub changed: usize,
+    pub unchanged: usize,
+}
+
+impl std::ops::AddAssign for ChangeCount {
+    fn add_assign(&mut self, other: ChangeCount) {
+        self.changed += other.changed;
+        self.unchanged += other.unchanged;
+    }
+}
+
+impl RunContext {
+    pub fn new(check: bool, diff: bool) -> RunContext {
+        RunContext {
+            check,
+            diff,
+            ..Default::default()
+        }
+    }
+
+    pub fn install(self, lua: &Lua) {
+        lua.set_app_data(self);
+    }
+}
+
+/// Whether the current run is in check mode.
+pub fn check_mode(lua: &Lua) -> bool {
+    lua.app_data_ref::<RunContext>()
+        .map(|c| c.check)
+        .unwrap_or(false)
+}
+
+/// Whether file changes should be shown as diffs.
+pub fn diff_mode(lua: &Lua) -> bool {
+    lua.app_data_ref::<RunContext>()
+        .map(|c| c.diff)
+        .unwrap_or(false)
+}
+
+/// Count one action towards the running task's changes. In check mode,
+/// `changed` means the action would have changed something.
+pub fn record_change(lua: &Lua, changed: bool) {
+    if let Some(ctx) = lua.app_data_ref::<RunContext>() {
+        let mut count = ctx.changes.get();
+        if changed {
+            count.changed += 1;
+        } else {
+            count.unchanged += 1;
+        }
+        ctx.changes.set(count);
+    }
+}
+
+/// Count a file or directory action towards the running task's changes, and
+/// remember the path if it was changed.
+pub fn record_file_change(lua: &Lua, path: &Path, changed: bool) {
+    record_change(lua, changed);
+    if !changed {
+        return;
+    }
+    if let Some(ctx) = lua.app_data_ref::<RunContext>() {
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let path = path.to_string_lossy().to_string();
+        let mut files = ctx.files.borrow_mut();
+        if !files.contains(&path) {
+            files.push(path);
+        }
+    }
+}
+
+/// Queue a handler to run at the next flush point. Notifying a handler
+/// that is already queued has no effect.
+pub fn notify(lua: &Lua, handler: &str) {
+    if let Some(ctx) = lua.app_data_ref::<RunContext>() {
+        let mut notified = ctx.notified.borrow_mut();
+        if !notified.iter().any(|h| h == handler) {
+            notified.push(handler.to_string());
+        }
+    }
+}
+
+/// Take the handlers notified since the last flush.
+pub fn take_notified(lua: &Lua) -> Vec<String> {
+    lua.app_data_ref::<RunContext>()
+        .map(|c| c.notified.take())
+        .unwrap_or_default()
+}
+
+/// Set or clear the running task's deadline.
+pub fn set_deadline(lua: &Lua, deadline: Option<Instant>) {
+    if let Some(ctx) = lua.app_data_ref::<RunContext>() {
+        ctx.deadline.set(deadline);
+    }
+}
+
+/// When the running task times out. Long-running actions should give up
+/// once it has passed.
+pub fn deadline(lua: &Lua) -> Option<Instant> {
+    lua.app_data_ref::<RunContext>()
+        .and_then(|c| c.deadline.get())
+}
+
+/// Take the paths changed since the last call.
+pub fn take_changed_files(lua: &Lua) -> Vec<String> {
+    lua.app_data_ref::<RunContext>()
+        .map(|c| c.files.take())
+        .unwrap_or_default()
+}
+
+/// Take the actions counted since the last call, resetting the count.
+pub fn take_changes(lua: &Lua) -> ChangeCount {
+    lua.app_data_ref::<RunContext>()
+        .map(|c| c.changes.take())
+        .unwrap_or_default()
+}
@@ -0,0 +1,422 @@
+use console::style;
+use mlua::{Lua, Table, Value};
+
+use crate::{error::TaskError, indent_output, output, secrets};
+
+use super::{roles, vars, Variables};
+
+/// Types a variable can be declared as.
+const TYPES: &[&str] = &["string", "integer", "number", "boolean", "table", "list"];
+
+/// Options a declaration can have.
+const OPTIONS: &[&str] = &["type", "required", "default", "description"];
+
+/// A variable declared with `declare_vars()`.
+struct Declared {
+    name: String,
+    ty: Option<String>,
+    required: bool,
+    default: Option<Value>,
+    description: Option<String>,
+    /// The role that declared it, which its value is looked up in.
+    role: Option<usize>,
+}
+
+impl Declared {
+    fn from_table(name: String, spec: Table, role: Option<usize>) -> Result<Declared, mlua::Error> {
+        for pair in spec.pairs::<Value, Value>() {
+            let (k, _) = pair?;
+            let known = matches!(&k, Value::String(s) if OPTIONS.contains(&&*s.to_str()?));
+            if !known {
+                return Err(mlua::Error::runtime(format!(
+                    "Unknown option '{}' for variable '{}', expected one of {}",
+                    k.to_string()?,
+                    name,
+                    OPTIONS.join(", ")
+                )));
+            }
+        }
+        let ty: Option<String> = spec.get("type")?;
+        if let Some(ty) = &ty {
+            if !TYPES.contains(&ty.as_str()) {
+                return Err(mlua::Error::runtime(format!(
+                    "Unknown type '{}' for variable '{}', expected one of {}",
+                    ty,
+                    name,
+                    TYPES.join(", ")
+                )));
+            }
+        }
+        let default: Option<Value> = spec.get("default")?;
+        let declared = Declared {
+            required: spec.get::<Option<bool>>("required")?.unwrap_or(false),
+            description: spec.get("description")?,
+            name,
+            ty,
+            default: None,
+            role,
+        };
+        if let Some(d) = &default {
+            if let Some(expected) = declared.mismatch(d) {
+                return Err(mlua::Error::runtime(format!(
+                    "Default for variable '{}' must be {}",
+                    declared.name, expected
+                )));
+            }
+        }
+        Ok(Declared {
+            default,
+            ..declared
+        })
+    }
+
+    fn to_table(&self, lua: &Lua) -> Result<Table, mlua::Error> {
+        let t = lua.create_table()?;
+        t.set("name", self.name.as_str())?;
+        t.set("type", self.ty.as_deref())?;
+        t.set("required", self.required)?;
+        t.set("default", self.default.clone())?;
+        t.set("description", self.description.as_deref())?;
+        t.set("role", self.role)?;
+        Ok(t)
+    }
+
+    fn from_registry(t: Table) -> Result<Declared, mlua::Error> {
+        Ok(Declared {
+            name: t.get("name")?,
+            ty: t.get("type")?,
+            required: t.get("required")?,
+            default: t.get("default")?,
+            description: t.get("description")?,
+            role: t.get("role")?,
+        })
+    }
+
+    /// The variables as seen where it was declared.
+    fn scope(&self, vars: &Variables) -> Variables {
+        match self.role {
+            Some(role) => vars.for_role(role),
+            None => vars.clone(),
+        }
+    }
+
+    /// The name, and the role that declared it, if any.
+    fn label(&self, lua: &Lua) -> Result<String, mlua::Error> {
+        Ok(match self.role {
+            Some(role) => format!("{} (role {})", self.name, roles::role_name(lua, role)?),
+            None => self.name.clone(),
+        })
+    }
+
+    /// What the value should have been, if it isn't of the declared type.
+    fn mismatch(&self, value: &Value) -> Option<&'static str> {
+        let ty = self.ty.as_deref()?;
+        let (matches, expected) = match ty {
+            "string" => (value.is_string(), "a string"),
+            "integer" => (
+                match value {
+                    Value::Integer(_) => true,
+                    Value::Number(n) => n.fract() == 0.0,
+                    _ => false,
+                },
+                "an integer",
+            ),
+            "number" => (value.is_number() || value.is_integer(), "a number"),
+            "boolean" => (value.is_boolean(), "a boolean"),
+            "table" => (value.is_table(), "a table"),
+            "list" => (
+                match value {
+                    Value::Table(t) => t.raw_len() == t.pairs::<Value, Value>().count(),
+                    _ => false,
+                },
+                "a list",
+            ),
+            _ => unreachable!("declared types are checked when declared"),
+        };
+        (!matches).then_some(expected)
+    }
+
+    /// Type, and whether it's required or what it defaults to.
+    fn summary(&self) -> Result<String, TaskError> {
+        let mut parts = vec![self.ty.clone().unwrap_or_else(|| "any".to_string())];
+        if self.required {
+            parts.push("required".to_string());
+        }
+        if let Some(d) = &self.default {
+            parts.push(format!("default {}", display(d.clone())?));
+        }
+        Ok(parts.join(", "))
+    }
+}
+
+/// A value as JSON, with secrets redacted.
+fn display(value: Value) -> Result<String, TaskError> {
+    Ok(secrets::redact(vars::lua_to_json(value)?.to_string()))
+}
+
+/// Every declared variable, in declaration order.
+fn declared(lua: &Lua) -> Result<Vec<Declared>, mlua::Error> {
+    let declarations: Table = lua.named_registry_value("var_declarations")?;
+    declarations
+        .sequence_values::<Table>()
+        .map(|t| Declared::from_registry(t?))
+        .collect()
+}
+
+/// Declare the variables in `specs`, for the given role or the whole config.
+pub(crate) fn declare(lua: &Lua, specs: Table, role: Option<usize>) -> Result<(), mlua::Error> {
+    let mut names = Vec::new();
+    for pair in specs.pairs::<String, Table>() {
+        names.push(pair?);
+    }
+    // Table order isn't stable, so declarations from one call are sorted
+    names.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let declarations: Table = lua.named_registry_value("var_declarations")?;
+    for (name, spec) in names {
+        let declared = Declared::from_table(name, spec, role)?;
+        if let Some(d) = &declared.default {
+            let vars: mlua::AnyUserData = lua.globals().get("vars")?;
+            declared.scope(&*vars.borrow::<Variables>()?).set_default(
+                lua,
+                &declared.name,
+                d.clone(),
+            )?;
+        }
+        // Declaring a variable again in the same place replaces it
+        let existing = declarations.sequence_values::<Table>().position(|t| {
+            t.and_then(Declared::from_registry)
+                .is_ok_and(|d| d.name == declared.name && d.role == declared.role)
+        });
+        match existing {
+            Some(i) => declarations.raw_set(i + 1, declared.to_table(lua)?)?,
+            None => declarations.raw_push(declared.to_table(lua)?)?,
+        }
+    }
+    Ok(())
+}
+
+/// Define `declare_vars()`, which declares the variables a config expects,
+/// with their types and defaults, so they can be checked before a run. Roles
+/// get their own, see `roles::role_env()`.
+pub(crate) fn define_declare_function(lua: &Lua) -> Result<(), TaskError> {
+    lua.set_named_registry_value("var_declarations", lua.create_table()?)?;
+    let f = lua.create_function(|ctx, specs: Table| declare(ctx, specs, None))?;
+    lua.globals().set("declare_vars", f)?;
+    Ok(())
+}
+
+/// Check the variables against their declarations, failing with every
+/// problem found. Variables that are set but not declared are only warned
+/// about, since inventory variables are often shared between configs.
+pub(crate) fn check(lua: &Lua, vars: &Variables) -> Result<(), TaskError> {
+    let declared = declared(lua)?;
+    if declared.is_empty() {
+        return Ok(());
+    }
+    let mut problems = Vec::new();
+    for d in declared.iter() {
+        match d.scope(vars).lookup(lua, &d.name)? {
+            None | Some(Value::Nil) => {
+                if d.required {
+                    problems.push(format!("'{}' is required but not set", d.label(lua)?));
+                }
+            }
+            Some(value) => {
+                if let Some(expected) = d.mismatch(&value) {
+                    let mut problem = format!(
+                        "'{}' must be {}, got {}",
+                        d.label(lua)?,
+                        expected,
+                        display(value.clone())?
+                    );
+                    if value.is_string() {
+                        problem.push_str(&format!(
+                            " (use -v {}:=VALUE to pass a typed value)",
+                            d.name
+                        ));
+                    }
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+    let undeclared = undeclared(&declared, vars);
+    if !undeclared.is_empty() {
+        output!(
+            "{}",
+            style(format!(
+                "Variables set but not declared: {}",
+                undeclared.join(", ")
+            ))
+            .yellow()
+        );
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(TaskError::InvalidVars(problems))
+    }
+}
+
+fn undeclared<'a>(declared: &[Declared], vars: &'a Variables) -> Vec<&'a str> {
+    vars.names()
+        .into_iter()
+        .filter(|n| !declared.iter().any(|d| d.name == *n))
+        .collect()
+}
+
+/// Print the declared variables with their current values, and any that are
+/// set but not declared.
+pub(crate) fn list(lua: &Lua, vars: &Variables) -> Result<(), TaskError> {
+    let declared = declared(lua)?;
+    if declared.is_empty() {
+        output!("No variables declared");
+        return Ok(());
+    }
+    output!("{}", style("Declared Variables").cyan());
+    for d in declared.iter() {
+        let value = match d.scope(vars).lookup(lua, &d.name)? {
+            None | Some(Value::Nil) if d.required => style("not set").red().to_string(),
+            None | Some(Value::Nil) => style("not set").dim().to_string(),
+            Some(v) => display(v)?,
+        };
+        indent_output!(
+            1,
+            "{} = {} {}",
+            style(d.label(lua)?).green(),
+            value,
+            style(format!("({})", d.summary()?)).dim()
+        );
+        if let Some(desc) = &d.description {
+            indent_output!(2, "{}", desc);
+        }
+    }
+    let undeclared = undeclared(&declared, vars);
+    if !undeclared.is_empty() {
+        output!("{}", style("Undeclared Variables").cyan());
+        for name in undeclared {
+            indent_output!(1, "{}", style(name).yellow());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A Lua state with `declare_vars()` and the given variables.
+    fn lua_with_vars(vars: serde_json::Value) -> (Lua, Variables) {
+        crate::tracker::init_for_tests();
+        let lua = Lua::new();
+        define_declare_function(&lua).unwrap();
+        let vars = Variables::from_json(vars);
+        lua.globals().set("vars", vars.clone()).unwrap();
+        (lua, vars)
+    }
+
+    fn problems(vars: serde_json::Value, declarations: &str) -> Vec<String> {
+        let (lua, vars) = lua_with_vars(vars);
+        lua.load(declarations).exec().unwrap();
+        match check(&lua, &vars) {
+            Ok(()) => Vec::new(),
+            Err(TaskError::InvalidVars(problems)) => problems,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn declaration_error(declarations: &str) -> String {
+        let (lua, _) = lua_with_vars(json!({}));
+        lua.load(declarations).exec().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_values_of_the_declared_type() {
+        let vars = json!({
+            "name": "web",
+            "count": 3,
+            "ratio": 0.5,
+            "debug": true,
+            "opts": {"a": 1},
+            "pkgs": ["a", "b"],
+        });
+        let declarations = r#"declare_vars {
+            name = { type = "string" },
+            count = { type = "integer" },
+            ratio = { type = "number" },
+            debug = { type = "boolean" },
+            opts = { type = "table" },
+            pkgs = { type = "list" },
+        }"#;
+        assert_eq!(problems(vars, declarations), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_every_mismatch() {
+        let vars = json!({
+            "count": 1.5,
+            "debug": "yes",
+            "pkgs": {"a": 1},
+            "ratio": "0.5",
+        });
+        let declarations = r#"declare_vars {
+            count = { type = "integer" },
+            debug = { type = "boolean" },
+            pkgs = { type = "list" },
+            ratio = { type = "number" },
+        }"#;
+        assert_eq!(
+            problems(vars, declarations),
+            vec![
+                "'count' must be an integer, got 1.5",
+                "'debug' must be a boolean, got \"yes\" (use -v debug:=VALUE to pass a typed value)",
+                "'pkgs' must be a list, got {\"a\":1}",
+                "'ratio' must be a number, got \"0.5\" (use -v ratio:=VALUE to pass a typed value)",
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_required_variables() {
+        let declarations = r#"declare_vars {
+            host = { type = "string", required = true },
+            port = { type = "integer", required = true, default = 80 },
+            user = { type = "string" },
+        }"#;
+        assert_eq!(
+            problems(json!({}), declarations),
+            vec!["'host' is required but not set"]
+        );
+        assert_eq!(
+            problems(json!({"host": "example.com"}), declarations),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn defaults_are_set_as_variables() {
+        let (lua, vars) = lua_with_vars(json!({}));
+        lua.load(r#"declare_vars { port = { type = "integer", default = 80 } }"#)
+            .exec()
+            .unwrap();
+        assert_eq!(vars.get(&lua, "port").unwrap().as_i64(), Some(80));
+    }
+
+    #[test]
+    fn rejects_bad_declarations() {
+        assert!(
+            declaration_error(r#"declare_vars { port = { type = "int" } }"#)
+                .contains("Unknown type 'int' for variable 'port'")
+        );
+        assert!(
+            declaration_error(r#"declare_vars { port = { requried = true } }"#)
+                .contains("Unknown option 'requried' for variable 'port'")
+        );
+        assert!(declaration_error(
+            r#"declare_vars { port = { type = "integer", default = "80" } }"#
+        )
+        .contains("Default for variable 'port' must be an integer"));
+    }
+}
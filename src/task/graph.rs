@@ -1,20 +1,52 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 
+use clap::ValueEnum;
 use petgraph::graph::DiGraph;
 use petgraph::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use super::{Task, TaskHandle, TaskRegistry};
+use super::{Task, TaskDep, TaskHandle, TaskRegistry};
+use crate::error::TaskError;
 
 pub type TaskIdx = NodeIndex<u32>;
 pub type TaskGraph = DiGraph<Task, (), u32>;
 
+/// Output formats for `--graph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// Node label: the task's variable name (if any) and description.
+fn node_label(registry: &TaskRegistry, task: &Task) -> String {
+    match registry.name_for_handle(task.id) {
+        Some(name) if name != task.description => format!("{}\n{}", name, task.description),
+        _ => task.description.clone(),
+    }
+}
+
+/// Name a task for error messages: its global name, or its description.
+fn task_label(registry: &TaskRegistry, task: &Task) -> String {
+    registry
+        .name_for_handle(task.id)
+        .unwrap_or_else(|| task.description.clone())
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 pub struct GraphState {
     dag: TaskGraph,
     id_graph_map: HashMap<TaskHandle, TaskIdx>,
 }
 
 impl GraphState {
-    pub fn from_registry(registry: TaskRegistry) -> Self {
+    /// Build the graph, resolving dependencies given by name. Fails if a
+    /// name doesn't belong to any task or the dependencies form a cycle.
+    pub fn from_registry(registry: TaskRegistry) -> Result<Self, TaskError> {
         let mut dag = TaskGraph::new();
         let mut id_graph_map = HashMap::new();
         for task in registry.tasks() {
@@ -25,15 +57,27 @@ impl GraphState {
 
         let mut dep_map: HashMap<TaskIdx, Vec<TaskIdx>> = HashMap::new();
 
-        // Unwraps are okay here because if the tasks DON'T exist, we already would
-        // have failed to exec the lua for missing references
+        // Task deps can't be missing, since they were passed in as Task userdata.
+        // Names are looked up here, now that every task has been defined.
         for task in dag.node_weights() {
             let idx = id_graph_map
                 .get(&task.id)
                 .expect("graph/id map out of sync");
             for dep in task.deps.iter() {
+                let dep_id = match dep {
+                    TaskDep::Task(id) => *id,
+                    TaskDep::Name(name) => {
+                        registry
+                            .task_for_name(name)
+                            .ok_or_else(|| TaskError::UnknownTask {
+                                task: task_label(&registry, task),
+                                name: name.clone(),
+                            })?
+                            .id
+                    }
+                };
                 let dep_idx = id_graph_map
-                    .get(&dep.id)
+                    .get(&dep_id)
                     .expect("Dep not found in task map");
                 dep_map.entry(*idx).or_default().push(*dep_idx);
             }
@@ -45,7 +89,61 @@ impl GraphState {
             }
         }
 
-        Self { dag, id_graph_map }
+        let state = Self { dag, id_graph_map };
+        if let Some(cycle) = state.find_cycle() {
+            return Err(TaskError::Cycle(
+                cycle
+                    .into_iter()
+                    .map(|idx| task_label(&registry, &state.dag[idx]))
+                    .collect(),
+            ));
+        }
+        Ok(state)
+    }
+
+    /// Find a dependency cycle, if there is one. The path starts and ends
+    /// with the same task, each task depending on the one after it.
+    fn find_cycle(&self) -> Option<Vec<TaskIdx>> {
+        // Tasks fully explored without finding a cycle
+        let mut done: HashSet<TaskIdx> = HashSet::new();
+        let mut starts: Vec<TaskIdx> = self.dag.node_indices().collect();
+        // Definition order, so the same cycle is reported every time
+        starts.sort_by_key(|&idx| self.dag[idx].id);
+        for start in starts {
+            if done.contains(&start) {
+                continue;
+            }
+            // The current path, and the dependencies left to visit at each step
+            let mut path = vec![start];
+            let mut pending = vec![self.sorted_deps(start)];
+            while let Some(deps) = pending.last_mut() {
+                if let Some(dep) = deps.pop() {
+                    if let Some(pos) = path.iter().position(|&p| p == dep) {
+                        let mut cycle = path[pos..].to_vec();
+                        cycle.push(dep);
+                        return Some(cycle);
+                    }
+                    if !done.contains(&dep) {
+                        path.push(dep);
+                        pending.push(self.sorted_deps(dep));
+                    }
+                } else {
+                    done.insert(path.pop().expect("path out of sync"));
+                    pending.pop();
+                }
+            }
+        }
+        None
+    }
+
+    /// Direct dependencies of a task, last defined first.
+    fn sorted_deps(&self, idx: TaskIdx) -> Vec<TaskIdx> {
+        let mut deps: Vec<TaskIdx> = self
+            .dag
+            .neighbors_directed(idx, Direction::Outgoing)
+            .collect();
+        deps.sort_by_key(|&d| std::cmp::Reverse(self.dag[d].id));
+        deps
     }
 
     // Determine all dependent tasks for the given task
@@ -81,6 +179,65 @@ impl GraphState {
             .collect()
     }
 
+    /// Render the given tasks and the dependencies between them. Edges point
+    /// from a dependency to the tasks that depend on it, in the direction
+    /// tasks run.
+    pub fn render(
+        &self,
+        registry: &TaskRegistry,
+        tasks: &[TaskHandle],
+        format: GraphFormat,
+    ) -> String {
+        let mut nodes: Vec<TaskIdx> = tasks.iter().map(|t| self.id_graph_map[t]).collect();
+        // Definition order keeps the output stable between runs
+        nodes.sort_by_key(|&idx| self.dag[idx].id);
+        let included: HashSet<TaskIdx> = nodes.iter().copied().collect();
+
+        let mut out = String::new();
+        match format {
+            GraphFormat::Dot => out.push_str("digraph hpg {\n    rankdir=LR;\n"),
+            GraphFormat::Mermaid => out.push_str("flowchart LR\n"),
+        }
+        for &idx in nodes.iter() {
+            let task = &self.dag[idx];
+            let label = node_label(registry, task);
+            let _ = match format {
+                GraphFormat::Dot => writeln!(
+                    out,
+                    "    t{} [label=\"{}\"];",
+                    task.id,
+                    dot_escape(&label).replace('\n', "\\n")
+                ),
+                GraphFormat::Mermaid => writeln!(
+                    out,
+                    "    t{}[\"{}\"]",
+                    task.id,
+                    label.replace('"', "#quot;").replace('\n', "<br>")
+                ),
+            };
+        }
+        for &idx in nodes.iter() {
+            let task = &self.dag[idx];
+            let mut deps: Vec<TaskIdx> = self
+                .dag
+                .neighbors_directed(idx, Direction::Outgoing)
+                .filter(|d| included.contains(d))
+                .collect();
+            deps.sort_by_key(|&d| self.dag[d].id);
+            for dep in deps {
+                let dep = &self.dag[dep];
+                let _ = match format {
+                    GraphFormat::Dot => writeln!(out, "    t{} -> t{};", dep.id, task.id),
+                    GraphFormat::Mermaid => writeln!(out, "    t{} --> t{}", dep.id, task.id),
+                };
+            }
+        }
+        if format == GraphFormat::Dot {
+            out.push_str("}\n");
+        }
+        out
+    }
+
     /// Find the safe-order execution plan for the given handles.
     /// Order is from deepest dependency to top-level.
     /// Duplicates are removed such that tasks will retain their highest priority in the execution order.
@@ -95,3 +252,98 @@ impl GraphState {
         execution
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry of tasks given as (description, name, deps), with ids in
+    /// the order given.
+    fn registry(tasks: &[(&str, Option<&str>, Vec<TaskDep>)]) -> TaskRegistry {
+        crate::tracker::init_for_tests();
+        let registry = TaskRegistry::new();
+        for (i, (description, name, deps)) in tasks.iter().enumerate() {
+            let task = Task::new(i + 1, description.to_string(), deps.clone());
+            if let Some(name) = name {
+                registry.register_name(task.id, *name);
+            }
+            registry.register_task(task);
+        }
+        registry
+    }
+
+    fn dep(id: usize) -> TaskDep {
+        TaskDep::Task(TaskHandle(id))
+    }
+
+    fn cycle(registry: TaskRegistry) -> Vec<String> {
+        match GraphState::from_registry(registry) {
+            Err(TaskError::Cycle(cycle)) => cycle,
+            Err(e) => panic!("expected a cycle, got {}", e),
+            Ok(_) => panic!("expected a cycle"),
+        }
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let graph = GraphState::from_registry(registry(&[
+            ("a", None, vec![]),
+            ("b", None, vec![dep(1)]),
+            ("c", None, vec![dep(1), dep(2)]),
+        ]))
+        .unwrap();
+        let order = graph.execution_for_tasks(&[TaskHandle(3)]);
+        assert_eq!(order, vec![TaskHandle(1), TaskHandle(2), TaskHandle(3)]);
+    }
+
+    #[test]
+    fn finds_self_dependency() {
+        let registry = registry(&[("a", Some("a"), vec![dep(1)])]);
+        assert_eq!(cycle(registry), vec!["a", "a"]);
+    }
+
+    #[test]
+    fn finds_cycle_through_tasks() {
+        let registry = registry(&[
+            ("ok", None, vec![]),
+            ("first", None, vec![dep(1), dep(3)]),
+            ("second", Some("second"), vec![dep(4)]),
+            ("third", None, vec![dep(2)]),
+        ]);
+        assert_eq!(cycle(registry), vec!["first", "second", "third", "first"]);
+    }
+
+    #[test]
+    fn finds_cycle_through_names() {
+        let registry = registry(&[
+            ("first", Some("a"), vec![TaskDep::Name("b".into())]),
+            ("second", Some("b"), vec![TaskDep::Name("a".into())]),
+        ]);
+        assert_eq!(cycle(registry), vec!["a", "b", "a"]);
+    }
+
+    #[test]
+    fn reports_the_same_cycle_every_time() {
+        let tasks = [
+            ("a", None, vec![dep(2)]),
+            ("b", None, vec![dep(1), dep(3)]),
+            ("c", None, vec![dep(2)]),
+        ];
+        let first = cycle(registry(&tasks));
+        for _ in 0..10 {
+            assert_eq!(cycle(registry(&tasks)), first);
+        }
+    }
+
+    #[test]
+    fn fails_on_unknown_name() {
+        let registry = registry(&[("a", Some("a"), vec![TaskDep::Name("missing".into())])]);
+        match GraphState::from_registry(registry) {
+            Err(TaskError::UnknownTask { task, name }) => {
+                assert_eq!(task, "a");
+                assert_eq!(name, "missing");
+            }
+            _ => panic!("expected an unknown task error"),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use mlua::{Function, Lua, Table, UserData, Value};
+
+use crate::{debug_output, error::TaskError, output};
+
+use super::context;
+
+/// A function that only runs when notified, once per flush.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handler {
+    name: String,
+}
+
+impl UserData for Handler {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("name", |_, this| Ok(this.name.clone()));
+    }
+}
+
+/// Defined handlers in definition order, as `(name, function)` pairs.
+pub(crate) fn defined_handlers(lua: &Lua) -> Result<Vec<(String, Function)>, mlua::Error> {
+    let handlers: Table = lua.named_registry_value("handlers")?;
+    let mut defined = Vec::new();
+    for h in handlers.sequence_values::<Table>() {
+        let h = h?;
+        defined.push((h.get("name")?, h.get("f")?));
+    }
+    Ok(defined)
+}
+
+fn handler_name(lua: &Lua, h: Value) -> Result<String, mlua::Error> {
+    let name = match h {
+        Value::UserData(ud) if ud.is::<Handler>() => ud.borrow::<Handler>()?.name.clone(),
+        Value::String(s) => s.to_str()?.to_string(),
+        _ => {
+            return Err(mlua::Error::runtime(
+                "notify() expects a handler or handler name",
+            ))
+        }
+    };
+    if !defined_handlers(lua)?.iter().any(|(n, _)| *n == name) {
+        return Err(mlua::Error::runtime(format!("Unknown handler '{}'", name)));
+    }
+    Ok(name)
+}
+
+/// Run notified handlers in definition order until none are left, including
+/// any that are notified by the handlers themselves.
+fn flush(lua: &Lua) -> Result<(), mlua::Error> {
+    let handlers = defined_handlers(lua)?;
+    loop {
+        let notified = context::take_notified(lua);
+        if notified.is_empty() {
+            return Ok(());
+        }
+        for (name, f) in handlers.iter() {
+            if notified.contains(name) {
+                output!("Handler [ {} ]", name);
+                f.call::<()>(())?;
+            }
+        }
+    }
+}
+
+/// Define the `handler()`, `notify()` and `flush_handlers()` functions.
+pub(crate) fn define_handler_functions(lua: &Lua) -> Result<(), TaskError> {
+    lua.set_named_registry_value("handlers", lua.create_table()?)?;
+
+    let handler = lua.create_function(|ctx, (name, f): (String, Function)| {
+        let handlers: Table = ctx.named_registry_value("handlers")?;
+        if defined_handlers(ctx)?.iter().any(|(n, _)| *n == name) {
+            return Err(mlua::Error::runtime(format!(
+                "Handler '{}' is already defined",
+                name
+            )));
+        }
+        let h = ctx.create_table()?;
+        h.set("name", name.as_str())?;
+        h.set("f", f)?;
+        handlers.push(h)?;
+        debug_output!("Registered handler '{}'", name);
+        Ok(Handler { name })
+    })?;
+    lua.globals().set("handler", handler)?;
+
+    let notify = lua.create_function(|ctx, (h, changed): (Value, Option<bool>)| {
+        let name = handler_name(ctx, h)?;
+        if changed == Some(false) {
+            return Ok(false);
+        }
+        context::notify(ctx, &name);
+        Ok(true)
+    })?;
+    lua.globals().set("notify", notify)?;
+
+    let flush_handlers = lua.create_function(|ctx, _: ()| flush(ctx))?;
+    lua.globals().set("flush_handlers", flush_handlers)?;
+    Ok(())
+}
@@ -1,20 +1,47 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
 
 use crate::{
-    debug_output, indent_output, output,
-    tracker::{self, Tracker},
+    actions::util,
+    debug_output, indent_output, output, secrets,
+    tracker::{self, StepDecision, StepPrompt, TaskRecap, TrackedTask, Tracker},
     Result,
 };
 use anyhow::anyhow;
 use console::style;
-use mlua::{self, FromLua, Function, Lua, LuaOptions, Table, UserData, Value, Variadic};
+use crossbeam::channel;
+use mlua::{
+    self, FromLua, Function, HookTriggers, Lua, LuaOptions, Table, UserData, Value, Variadic,
+    VmState,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::error::TaskError;
+mod callbacks;
+pub mod context;
+mod declared;
 pub mod graph;
+mod handlers;
+pub mod options;
 pub mod vars;
 pub use vars::Variables;
 pub mod registry;
-use self::{graph::GraphState, registry::TaskRegistry};
+pub mod report;
+pub mod roles;
+mod state;
+use self::{
+    context::{ChangeCount, RunContext},
+    graph::{GraphFormat, GraphState},
+    options::TaskOptions,
+    registry::TaskRegistry,
+    report::{RunReport, TaskReport, Timing},
+    state::{LastRun, TaskKey},
+};
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct TaskHandle(usize);
@@ -29,11 +56,32 @@ impl Display for TaskHandle {
 pub struct Task {
     pub id: TaskHandle,
     description: String,
-    deps: Vec<Task>,
+    deps: Vec<TaskDep>,
+}
+
+/// A dependency given to `task()`, either the task itself or the global name
+/// it's assigned to. Names are resolved once the whole config has been
+/// evaluated, so they can refer to tasks defined later on.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TaskDep {
+    Task(TaskHandle),
+    Name(String),
+}
+
+impl FromLua for TaskDep {
+    fn from_lua(value: Value, lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(s) => Ok(TaskDep::Name(s.to_str()?.to_string())),
+            Value::UserData(_) => Ok(TaskDep::Task(Task::from_lua(value, lua)?.id)),
+            _ => Err(mlua::Error::external(anyhow!(
+                "Task dependencies must be a task, a task name or a sequence of them"
+            ))),
+        }
+    }
 }
 
 impl Task {
-    pub fn new(id: usize, description: String, deps: Vec<Task>) -> Task {
+    pub fn new(id: usize, description: String, deps: Vec<TaskDep>) -> Task {
         Task {
             id: TaskHandle(id),
             description,
@@ -64,53 +112,296 @@ impl FromLua for Task {
     }
 }
 
-impl UserData for Task {}
+impl UserData for Task {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("output", |ctx, this, ()| {
+            let outputs: Table = ctx.named_registry_value("task_outputs")?;
+            outputs.get::<Value>(this.id.0)
+        });
+    }
+}
 #[derive(Debug, Clone)]
 pub enum IncompleteReason {
     Skipped,
     Failed,
-    Cancelled,
+    /// Never started, because an earlier task failed and stopped the run.
+    NotRun,
+    /// Cancelled by the task itself with `cancel()`, with an optional reason.
+    Cancelled(Option<String>),
 }
 
 #[derive(Debug, Clone)]
 pub enum TaskResult {
-    Success,
+    /// Completed, and at least one action changed the system.
+    Changed,
+    /// Completed without changing anything.
+    Unchanged,
+    /// Not run because its `when` condition was false, with the reason.
+    /// Dependents still run.
+    NotApplicable(String),
     Incomplete(IncompleteReason),
 }
 
 impl TaskResult {
-    #[allow(dead_code)]
     pub fn succeeded(&self) -> bool {
         match self {
-            TaskResult::Success => true,
+            TaskResult::Changed | TaskResult::Unchanged | TaskResult::NotApplicable(_) => true,
             TaskResult::Incomplete(_) => false,
         }
     }
 
     pub fn incomplete(&self) -> bool {
-        match self {
-            TaskResult::Success => false,
-            TaskResult::Incomplete(_) => true,
-        }
+        !self.succeeded()
     }
 
     pub fn errored(&self) -> bool {
-        match self {
-            TaskResult::Incomplete(IncompleteReason::Failed) => true,
-            _ => false,
-        }
+        matches!(self, TaskResult::Incomplete(IncompleteReason::Failed))
     }
 }
 
 impl UserData for TaskResult {}
 
+/// The result of running a task, with the changes its actions reported.
+#[derive(Debug, Clone)]
+pub struct TaskOutcome {
+    pub result: TaskResult,
+    pub changes: ChangeCount,
+    /// Files and directories the task changed.
+    pub files: Vec<String>,
+    /// Handlers notified and not yet flushed when the task finished. Only
+    /// set by parallel workers, to hand them over to the main state.
+    pub notified: Vec<String>,
+    /// Error message and Lua traceback, if the task failed.
+    pub error: Option<String>,
+    pub traceback: Option<String>,
+    /// Unset if the task body never ran.
+    pub timing: Option<Timing>,
+    /// The table returned by the task body, if any.
+    pub output: Option<serde_json::Value>,
+}
+
+impl TaskOutcome {
+    fn new(result: TaskResult) -> TaskOutcome {
+        TaskOutcome {
+            result,
+            changes: ChangeCount::default(),
+            files: Vec::new(),
+            notified: Vec::new(),
+            error: None,
+            traceback: None,
+            timing: None,
+            output: None,
+        }
+    }
+
+    fn skipped() -> TaskOutcome {
+        TaskOutcome::new(TaskResult::Incomplete(IncompleteReason::Skipped))
+    }
+
+    fn recap(&self, task: String) -> TaskRecap {
+        TaskRecap {
+            task,
+            ok: self.changes.unchanged,
+            changed: self.changes.changed,
+            skipped: usize::from(
+                matches!(self.result, TaskResult::NotApplicable(_))
+                    || (self.result.incomplete() && !self.result.errored()),
+            ),
+            failed: usize::from(self.result.errored()),
+        }
+    }
+}
+
+/// Options controlling how the requested tasks are executed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecOptions {
+    pub run_defaults: bool,
+    pub show_plan: bool,
+    /// Maximum number of tasks to run concurrently.
+    pub jobs: usize,
+    /// Report what would change without changing anything.
+    pub check: bool,
+    /// Print a diff for every file that is changed.
+    pub diff: bool,
+    /// Run notified handlers even if a task failed.
+    pub force_handlers: bool,
+    /// Keep running tasks that don't depend on a failed task.
+    pub keep_going: bool,
+    /// Skip every task before this one in the execution order.
+    pub start_at: Option<String>,
+    /// Only run tasks that didn't complete in the last run.
+    pub resume: bool,
+    /// Also run every task with one of these tags.
+    pub tags: Vec<String>,
+    /// Leave out tasks with any of these tags.
+    pub skip_tags: Vec<String>,
+    /// Ask before running each task.
+    pub step: bool,
+}
+
+impl ExecOptions {
+    fn run_context(&self) -> RunContext {
+        RunContext::new(self.check, self.diff)
+    }
+}
+
+/// Registration function for a builtin Lua module or action.
+pub type Builtin = fn(&Lua) -> Result<(), TaskError>;
+
 fn std_lib() -> mlua::StdLib {
     use mlua::StdLib;
     StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH | StdLib::PACKAGE
 }
 
-fn find_tasks(table: Table, registry: &TaskRegistry) -> Result<(), mlua::Error> {
-    for pair in table.pairs() {
+/// Everything given to `task()` or `task_each()` after the description.
+struct TaskArgs {
+    deps: Vec<TaskDep>,
+    options: TaskOptions,
+    when: Option<Function>,
+    body: Option<Function>,
+}
+
+impl TaskArgs {
+    fn parse(
+        ctx: &Lua,
+        fn_name: &str,
+        deps_or_f: Value,
+        maybe_f: Option<Function>,
+    ) -> mlua::Result<TaskArgs> {
+        let mut args = TaskArgs {
+            deps: Vec::new(),
+            options: TaskOptions::default(),
+            when: None,
+            body: None,
+        };
+        match deps_or_f {
+            // Task deps table must be a sequence of Tasks or task names.
+            // Named fields are task options.
+            Value::Table(t) => {
+                args.options = TaskOptions::from_table(&t)?;
+                args.when = t.get::<Option<Function>>("when")?;
+                args.deps = t.sequence_values().collect::<Result<Vec<TaskDep>, _>>()?;
+            }
+            // Single values must be a task or a task name
+            Value::UserData(_) | Value::String(_) => {
+                args.deps.push(TaskDep::from_lua(deps_or_f, ctx)?);
+            }
+            // No dependencies, only a task function
+            Value::Function(f) => {
+                args.body = Some(f);
+            }
+            // task_each() with only a description and items
+            Value::Nil if fn_name == "task_each" => {}
+            _ => {
+                return Err(mlua::Error::external(anyhow!(
+                    "Invalid signature for {}() function, param not table, task, task name, or function: {:?}",
+                    fn_name,
+                    deps_or_f
+                )))
+            }
+        };
+
+        if let Some(f) = maybe_f {
+            if args.body.is_some() {
+                // This means the dependency argument was also a function, this is invalid.
+                return Err(mlua::Error::external(anyhow!(
+                    "Invalid signature for {}() function, two functions",
+                    fn_name
+                )));
+            }
+            args.body = Some(f);
+        }
+        Ok(args)
+    }
+}
+
+/// Add a task to the registry, storing its body and condition in the Lua
+/// registry.
+fn register_task(
+    ctx: &Lua,
+    registry: &TaskRegistry,
+    desc: String,
+    deps: Vec<TaskDep>,
+    options: TaskOptions,
+    when: Option<Function>,
+    body: Option<Function>,
+) -> mlua::Result<Task> {
+    let i = registry.next_id();
+    if let Some(f) = body {
+        let task_table: Table = ctx.named_registry_value("tasks")?;
+        task_table.set(i, f)?;
+    }
+    if let Some(when) = when {
+        let conditions: Table = ctx.named_registry_value("task_conditions")?;
+        conditions.set(i, when)?;
+    }
+
+    let task = Task::new(i, desc, deps);
+    debug_output!("Registered task '{}'", task.description());
+    registry.register_task(task.clone());
+    registry.register_options(task.id, options);
+    Ok(task)
+}
+
+/// Name prefix for the tasks `task_each()` defines, e.g. `deploy_site` for
+/// "deploy site".
+fn name_prefix(desc: &str) -> String {
+    desc.chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// What a `task_each()` item is called in its task's name and description:
+/// strings and numbers as they are, tables by their `name` field, and
+/// anything else by its position in the list.
+fn item_key(idx: usize, item: &Value) -> mlua::Result<String> {
+    Ok(match item {
+        Value::String(s) => s.to_str()?.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Table(t) => t
+            .get::<Option<String>>("name")?
+            .unwrap_or_else(|| idx.to_string()),
+        _ => idx.to_string(),
+    })
+}
+
+/// Standard libraries, which are in `package.loaded` but never hold tasks.
+const STD_MODULES: &[&str] = &[
+    "_G",
+    "coroutine",
+    "debug",
+    "io",
+    "math",
+    "os",
+    "package",
+    "string",
+    "table",
+    "utf8",
+];
+
+/// Register the names of tasks assigned to globals, and of tasks in module
+/// tables returned by `require`, e.g. `web.nginx` for the `nginx` field of
+/// module `web`.
+fn find_tasks(lua: &Lua, registry: &TaskRegistry) -> Result<(), mlua::Error> {
+    let globals = lua.globals();
+    if let Some(package) = globals.get::<Option<Table>>("package")? {
+        let loaded: Table = package.get("loaded")?;
+        let mut seen = HashSet::new();
+        for pair in loaded.pairs::<String, Value>() {
+            let (module, val) = pair?;
+            if !STD_MODULES.contains(&module.as_str()) {
+                register_names(&module, val, registry, &mut seen)?;
+            }
+        }
+    }
+    for pair in globals.pairs() {
         let (name, val): (String, Value) = pair?;
         match val {
             Value::UserData(ud) => {
@@ -125,9 +416,36 @@ fn find_tasks(table: Table, registry: &TaskRegistry) -> Result<(), mlua::Error>
     Ok(())
 }
 
+/// Register `val` as `name` if it's a task. Tables are searched for tasks,
+/// which are named `name.field`.
+fn register_names(
+    name: &str,
+    val: Value,
+    registry: &TaskRegistry,
+    seen: &mut HashSet<*const std::ffi::c_void>,
+) -> Result<(), mlua::Error> {
+    match val {
+        Value::UserData(ud) if ud.is::<Task>() => {
+            registry.register_name(ud.borrow::<Task>()?.id, name);
+        }
+        // Tables can refer to each other, only look in each one once
+        Value::Table(t) if seen.insert(t.to_pointer()) => {
+            for pair in t.pairs::<Value, Value>() {
+                if let (Value::String(key), val) = pair? {
+                    let name = format!("{}.{}", name, key.to_str()?);
+                    register_names(&name, val, registry, seen)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
 pub struct LuaState {
     lua: Lua,
     registry: TaskRegistry,
+    builtins: Vec<Builtin>,
 }
 
 impl LuaState {
@@ -135,14 +453,25 @@ impl LuaState {
         let lua = Lua::new_with(std_lib(), LuaOptions::new()).unwrap();
         let registry = TaskRegistry::new();
 
-        Ok(Self { lua, registry })
+        Ok(Self {
+            lua,
+            registry,
+            builtins: Vec::new(),
+        })
     }
 
-    pub fn register_fn<F>(&self, f: F) -> Result<()>
-    where
-        F: Fn(&Lua) -> Result<(), TaskError>,
-    {
+    /// Create a fresh state with the given builtins already registered.
+    fn with_builtins(builtins: &[Builtin]) -> Result<Self> {
+        let mut state = Self::new()?;
+        for f in builtins {
+            state.register_fn(*f)?;
+        }
+        Ok(state)
+    }
+
+    pub fn register_fn(&mut self, f: Builtin) -> Result<()> {
         f(&self.lua)?;
+        self.builtins.push(f);
         Ok(())
     }
 
@@ -150,84 +479,79 @@ impl LuaState {
         let task_table = self.lua.create_table()?;
         let lua = &self.lua;
         lua.set_named_registry_value("tasks", task_table)?;
+        lua.set_named_registry_value("task_conditions", lua.create_table()?)?;
+        lua.set_named_registry_value("task_outputs", lua.create_table()?)?;
         let registry = self.registry.clone();
         let f = lua.create_function(
             move |ctx, (desc, deps_or_f, maybe_f): (String, Value, Option<Function>)| {
-                let mut task_deps = Vec::new();
-                let mut task_fn = None;
-                // Handle first argument to task() function
-                match deps_or_f {
-                    // Task deps table must be a sequence of UserData, and the UserData must be a Task
-                    Value::Table(t) => {
-                        let deps: Vec<Value> =
-                            t.sequence_values().collect::<Result<Vec<Value>, _>>()?;
-                        for dep in deps {
-                            match dep {
-                                Value::UserData(ud) => {
-                                    if ud.is::<Task>() {
-                                        let t: &Task = &*ud.borrow::<Task>()?;
-                                        task_deps.push(t.clone());
-                                    } else {
-                                        return Err(mlua::Error::external(anyhow!(
-                                            "Task dependencies must be a task or sequence of tasks"
-                                        )));
-                                    }
-                                }
-                                _ => {
-                                    return Err(mlua::Error::external(anyhow!(
-                                        "Invalid signature for task() function, in dep table"
-                                    )));
-                                }
-                            }
-                        }
-                    }
-                    // Single userdata values must be a task
-                    Value::UserData(ud) => {
-                        if ud.is::<Task>() {
-                            let ts: &Task = &*ud.borrow::<Task>()?;
-                            task_deps.push(ts.clone());
-                        } else {
-                            return Err(mlua::Error::external(anyhow!(
-                                "Task dependencies must be a task or sequence of tasks"
-                            )));
-                        }
-                    }
-                    // No dependencies, only a task function
-                    Value::Function(f) => {
-                        task_fn = Some(f);
-                    }
-                    _ => {
-                        return Err(mlua::Error::external(anyhow!(
-                            "Invalid signature for task() function, second param not table, task, or function: {:?}", deps_or_f 
-                        )))
-                    }
-                };
-
-                if let Some(f) = maybe_f {
-                    if task_fn.is_some() {
-                        // This means the second argument was also a function, this is invalid.
-                        return Err(mlua::Error::external(anyhow!(
-                            "Invalid signature for task() function, two functions"
-                        )));
-                    }
-                    task_fn = Some(f);
-                }
+                let args = TaskArgs::parse(ctx, "task", deps_or_f, maybe_f)?;
+                register_task(
+                    ctx,
+                    &registry,
+                    desc,
+                    args.deps,
+                    args.options,
+                    args.when,
+                    args.body,
+                )
+            },
+        )?;
+        self.lua.globals().set("task", f)?;
 
-                let task_table: Table = ctx.named_registry_value("tasks")?;
-                let i = registry.next_id();
-                if let Some(f) = task_fn {
-                    task_table.set(i, f)?;
+        let registry = self.registry.clone();
+        let f = lua.create_function(
+            move |ctx,
+                  (desc, items, deps_or_f, maybe_f): (
+                String,
+                Vec<Value>,
+                Value,
+                Option<Function>,
+            )| {
+                let args = TaskArgs::parse(ctx, "task_each", deps_or_f, maybe_f)?;
+                let prefix = name_prefix(&desc);
+                let mut subtasks = Vec::new();
+                for (idx, item) in items.into_iter().enumerate() {
+                    let key = item_key(idx + 1, &item)?;
+                    let body = args.body.as_ref().map(|f| f.bind(&item)).transpose()?;
+                    let when = args.when.as_ref().map(|f| f.bind(&item)).transpose()?;
+                    let task = register_task(
+                        ctx,
+                        &registry,
+                        format!("{} [{}]", desc, key),
+                        args.deps.clone(),
+                        args.options.clone(),
+                        when,
+                        body,
+                    )?;
+                    registry.register_name(task.id, format!("{}[{}]", prefix, key));
+                    subtasks.push(TaskDep::Task(task.id));
                 }
-                ctx.set_named_registry_value("tasks", task_table)?;
-
-                let task = Task::new(i, desc, task_deps);
-                debug_output!("Registered task '{}'", task.description());
-                registry.register_task(task.clone());
-                Ok(task)
+                // Depending on the returned task means depending on every item
+                let group_options = TaskOptions {
+                    tags: args.options.tags,
+                    ..Default::default()
+                };
+                register_task(ctx, &registry, desc, subtasks, group_options, None, None)
             },
         )?;
+        self.lua.globals().set("task_each", f)?;
 
-        self.lua.globals().set("task", f)?;
+        // `outputs.<name>` looks up outputs by task name
+        let registry = self.registry.clone();
+        let outputs = lua.create_table()?;
+        let meta = lua.create_table()?;
+        meta.set(
+            "__index",
+            lua.create_function(move |ctx, (_, name): (Table, String)| {
+                let Some(task) = registry.task_for_name(&name) else {
+                    return Err(mlua::Error::runtime(format!("Unknown task '{}'", name)));
+                };
+                let outputs: Table = ctx.named_registry_value("task_outputs")?;
+                outputs.get::<Value>(task.id.0)
+            })?,
+        )?;
+        outputs.set_metatable(Some(meta));
+        lua.globals().set("outputs", outputs)?;
         Ok(())
     }
 
@@ -239,7 +563,7 @@ impl LuaState {
             .lua
             .create_function(move |ctx, tasks: Variadic<Value>| {
                 // At call time, make sure to gather all named tasks to have up-to-date availability
-                find_tasks(ctx.globals(), &registry)?;
+                find_tasks(ctx, &registry)?;
                 let mut targets: Vec<Task> = ctx.named_registry_value("targets")?;
                 for t in tasks.iter() {
                     match t {
@@ -280,9 +604,30 @@ impl LuaState {
         Ok(())
     }
 
+    fn define_export_function(&self) -> Result<(), TaskError> {
+        let registry = self.registry.clone();
+        let f = self.lua.create_function(move |_, tasks: Table| {
+            let mut seen = HashSet::new();
+            for pair in tasks.clone().pairs::<Value, Value>() {
+                match pair? {
+                    (Value::String(name), val) => {
+                        register_names(&name.to_str()?, val, &registry, &mut seen)?
+                    }
+                    _ => {
+                        return Err(mlua::Error::runtime(
+                            "export() expects a table of names to tasks",
+                        ))
+                    }
+                }
+            }
+            Ok(tasks)
+        })?;
+        self.lua.globals().set("export", f)?;
+        Ok(())
+    }
+
     fn find_tasks(&self) -> Result<(), TaskError> {
-        let globals = self.lua.globals();
-        find_tasks(globals, &self.registry)?;
+        find_tasks(&self.lua, &self.registry)?;
         Ok(())
     }
 
@@ -294,18 +639,37 @@ impl LuaState {
     pub fn eval(self, src: &str, v: Variables) -> Result<EvaluatedLuaState> {
         self.define_task_function()?;
         self.define_target_function()?;
+        self.define_export_function()?;
+        handlers::define_handler_functions(&self.lua)?;
+        callbacks::define_callback_functions(&self.lua)?;
+        roles::define_role_function(&self.lua)?;
+        declared::define_declare_function(&self.lua)?;
+        // Variables decrypted by the client arrive in plaintext on the server
+        secrets::register(v.secrets());
         self.lua
             .globals()
-            .set("vars", v)
+            .set("vars", v.clone())
             .map_err(|e| TaskError::Action(format!("Couldn't set vars global: {}", e)))?;
 
-        self.eval_string(src)?;
-        self.find_tasks()?;
-        let graph = GraphState::from_registry(self.registry.clone());
+        // Counts the actions run outside of tasks
+        RunContext::default().install(&self.lua);
+        if let Err(e) = self.eval_string(src).and_then(|_| self.find_tasks()) {
+            // Reading a declared variable that wasn't set fails the config, so
+            // report every declared problem rather than the first lookup error
+            declared::check(&self.lua, &v)?;
+            return Err(e.into());
+        }
+        let top_level_actions = context::take_changes(&self.lua);
+        context::take_changed_files(&self.lua);
+        let graph = GraphState::from_registry(self.registry.clone())?;
         Ok(EvaluatedLuaState {
             lua: self.lua,
             registry: self.registry,
             graph,
+            builtins: self.builtins,
+            src: src.to_string(),
+            vars: v,
+            top_level_actions: top_level_actions.changed + top_level_actions.unchanged,
         })
     }
 }
@@ -314,6 +678,11 @@ pub struct EvaluatedLuaState {
     lua: Lua,
     registry: TaskRegistry,
     graph: GraphState,
+    builtins: Vec<Builtin>,
+    src: String,
+    vars: Variables,
+    /// Actions the config ran outside of tasks while it was evaluated.
+    top_level_actions: usize,
 }
 
 impl EvaluatedLuaState {
@@ -339,18 +708,162 @@ impl EvaluatedLuaState {
         Ok(targets)
     }
 
+    /// Tasks selected with `--tags`, in definition order.
+    fn tagged_targets(&self, tags: &[String]) -> Result<Vec<TaskHandle>, TaskError> {
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tagged = self.registry.tasks_tagged(tags);
+        if tagged.is_empty() {
+            return Err(TaskError::Action(format!(
+                "No tasks tagged {}",
+                tags.join(", ")
+            )));
+        }
+        Ok(tagged)
+    }
+
+    /// Drop tasks with any of the `--skip-tags` from `ordering`.
+    fn without_skipped_tags(&self, ordering: &mut Vec<TaskHandle>, skip_tags: &[String]) {
+        if !skip_tags.is_empty() {
+            let skipped = self.registry.tasks_tagged(skip_tags);
+            ordering.retain(|t| !skipped.contains(t));
+        }
+    }
+
+    /// Render the dependency graph of the requested tasks (plus default and
+    /// tagged targets), or of every task if none are requested.
+    pub fn graph(
+        &self,
+        tasks: &[&str],
+        opts: &ExecOptions,
+        format: GraphFormat,
+    ) -> Result<String, TaskError> {
+        let mut requested: Vec<TaskHandle> =
+            self.get_targets(tasks)?.into_iter().map(|t| t.id).collect();
+        if opts.run_defaults {
+            requested.extend(self.get_default_targets()?.into_iter().map(|t| t.id));
+        }
+        requested.extend(self.tagged_targets(&opts.tags)?);
+        let mut handles = if requested.is_empty() {
+            let mut all: Vec<TaskHandle> =
+                self.registry.tasks().into_iter().map(|t| t.id).collect();
+            all.sort();
+            all
+        } else {
+            self.execution_ordering(&requested)
+        };
+        self.without_skipped_tags(&mut handles, &opts.skip_tags);
+        Ok(self.graph.render(&self.registry, &handles, format))
+    }
+
     pub fn available_targets(&self) -> Vec<(String, Task)> {
         self.registry.named_tasks().into_iter().collect()
     }
 
+    /// Print the named tasks with their tags, and every tag in use.
+    pub fn list_targets(&self) {
+        output!("{}", style("Available Tasks").cyan());
+        for (name, task) in self.available_targets() {
+            let tags = self.registry.options_for_handle(task.id).tags;
+            if tags.is_empty() {
+                indent_output!(1, "{}: {}", style(name).green(), task.description());
+            } else {
+                indent_output!(
+                    1,
+                    "{}: {} {}",
+                    style(name).green(),
+                    task.description(),
+                    style(format!("[{}]", tags.join(", "))).yellow()
+                );
+            }
+        }
+        let tags = self.registry.tags();
+        if !tags.is_empty() {
+            output!("{}", style("Available Tags").cyan());
+            for tag in tags {
+                indent_output!(1, "{}", style(tag).yellow());
+            }
+        }
+    }
+
+    /// Print the variables the config sees, or the one at `key`. With
+    /// `explain`, print every value with the source it came from.
+    pub fn show_vars(&self, key: Option<&str>, explain: bool) -> Result<(), TaskError> {
+        if !explain {
+            let value = self.vars.resolve(&self.lua, key)?.ok_or_else(|| {
+                TaskError::Action(format!("Variable '{}' not defined", key.unwrap_or("")))
+            })?;
+            let json = serde_json::to_string_pretty(&value)
+                .map_err(|e| TaskError::Action(e.to_string()))?;
+            output!("{}", json);
+            return Ok(());
+        }
+        let explained = self.vars.explain(&self.lua, key)?;
+        if explained.is_empty() {
+            if let Some(key) = key {
+                return Err(TaskError::Action(format!("Variable '{}' not defined", key)));
+            }
+        }
+        // Redacted up front, so secrets don't throw off the alignment
+        let lines: Vec<(String, String)> = explained
+            .into_iter()
+            .map(|v| {
+                let value = secrets::redact(v.value.to_string());
+                (format!("{} = {}", style(&v.key).green(), value), v.source)
+            })
+            .collect();
+        let width = lines
+            .iter()
+            .map(|(assignment, _)| console::measure_text_width(assignment))
+            .max()
+            .unwrap_or(0);
+        for (assignment, source) in lines {
+            output!(
+                "{}  {}",
+                console::pad_str(&assignment, width, console::Alignment::Left, None),
+                style(source).dim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Print the variables declared with `declare_vars()` and their values.
+    pub fn list_vars(&self) -> Result<(), TaskError> {
+        declared::list(&self.lua, &self.vars)
+    }
+
+    /// Run the requested tasks, returning a report of the run. `None` if only
+    /// the execution plan was shown.
     pub fn execute(
         &self,
         tasks: &[&str],
-        run_default_targets: bool,
-        show_plan: bool,
-    ) -> Result<(), TaskError> {
+        opts: &ExecOptions,
+    ) -> Result<Option<RunReport>, TaskError> {
+        // Before anything runs, so every problem is reported at once
+        declared::check(&self.lua, &self.vars)?;
+        // Workers evaluate the config again, which would repeat them
+        if opts.jobs > 1 && !opts.step && self.top_level_actions > 0 {
+            return Err(TaskError::Action(format!(
+                "The config runs {} action(s) outside of tasks, which every --jobs worker \
+                 would run again. Move them into tasks, or run without --jobs.",
+                self.top_level_actions
+            )));
+        }
+        let last_run = if opts.resume {
+            Some(LastRun::load()?)
+        } else {
+            None
+        };
+        // Resuming without targets picks up the targets of the last run
+        let resumed_targets = match &last_run {
+            Some(last) if tasks.is_empty() && !opts.run_defaults => {
+                last.target_handles(&self.registry)?
+            }
+            _ => Vec::new(),
+        };
         let mut requested_tasks = self.get_targets(tasks)?;
-        if run_default_targets {
+        if opts.run_defaults {
             let defaults = self.get_default_targets()?;
             if !defaults.is_empty() {
                 output!("{}", style("Default Targets").cyan());
@@ -360,87 +873,949 @@ impl EvaluatedLuaState {
             }
             requested_tasks.extend(defaults);
         }
-        let requested_handles: Vec<TaskHandle> =
-            requested_tasks.into_iter().map(|t| t.id).collect();
+        let mut requested_handles: Vec<TaskHandle> = resumed_targets;
+        requested_handles.extend(requested_tasks.into_iter().map(|t| t.id));
+        requested_handles.extend(self.tagged_targets(&opts.tags)?);
+        let targets: Vec<String> = requested_handles
+            .iter()
+            .map(|&t| self.task_label(t))
+            .collect();
+        let target_keys: Vec<TaskKey> = requested_handles
+            .iter()
+            .map(|&t| TaskKey::new(&self.registry.task_for_handle(t)))
+            .collect();
 
-        let ordering = self.execution_ordering(&requested_handles);
-        if show_plan {
+        let mut ordering = self.execution_ordering(&requested_handles);
+        self.without_skipped_tags(&mut ordering, &opts.skip_tags);
+        let (already_done, ordering) =
+            self.split_completed(ordering, opts.start_at.as_deref(), last_run.as_ref())?;
+        if !already_done.is_empty() {
+            output!("{}", style("Already Done").cyan());
+            for &t in already_done.iter() {
+                indent_output!(1, "{}", self.registry.task_for_handle(t).description);
+            }
+        }
+        if opts.show_plan {
             output!("{}", style("Execution Plan").yellow());
             for (idx, handle) in ordering.into_iter().enumerate() {
                 let t = self.registry.task_for_handle(handle);
-                indent_output!(1, "{}. {}", idx + 1, t.description);
+                if self.condition(handle)?.is_some() {
+                    indent_output!(
+                        1,
+                        "{}. {} {}",
+                        idx + 1,
+                        t.description,
+                        style("(when)").yellow()
+                    );
+                } else {
+                    indent_output!(1, "{}. {}", idx + 1, t.description);
+                }
             }
-            return Ok(());
+            return Ok(None);
         }
 
-        let mut task_results: HashMap<TaskHandle, TaskResult> = HashMap::new();
-        let task_table: Table = self.lua.named_registry_value("tasks")?;
+        let start = Utc::now();
+        opts.run_context().install(&self.lua);
         tracker::tracker().run(ordering.len());
         output!("{}", style("Execution").yellow());
-        for task in ordering {
-            let t = self.registry.task_for_handle(task);
-            tracker::tracker().task(t.description.clone());
-            output!("Task [ {} ]", style(t.description.clone()).cyan());
-            let mut parent_failed = false;
-
-            // Did all our parents run successfully?
-            for parent in self.graph.direct_parents(task) {
-                // unwrap is safe because we're guaranteed to execute parents first due to ordering
-                match task_results.get(&parent).unwrap() {
-                    TaskResult::Success => {}
-                    TaskResult::Incomplete(_) => {
-                        tracker::tracker().task_skip();
-                        parent_failed = true;
-                        break;
+        if opts.check {
+            output!(
+                "{}",
+                style("Check mode: changes will be reported, not applied").magenta()
+            );
+        }
+        // Prompts for --step come one at a time
+        let mut task_results = if opts.jobs > 1 && !opts.step {
+            self.execute_parallel(&ordering, opts)?
+        } else {
+            self.execute_sequential(&ordering, opts)?
+        };
+        // Every task in the plan gets a status, even if the run stopped first
+        for &task in ordering.iter() {
+            task_results.entry(task).or_insert_with(|| {
+                TaskOutcome::new(TaskResult::Incomplete(IncompleteReason::NotRun))
+            });
+        }
+        let mut recap = self.recap(&ordering, &task_results);
+        let mut incomplete = task_results.values().any(|o| o.result.incomplete());
+
+        // Handlers run once each, in definition order, no matter how many
+        // tasks notified them. Handlers already run by `flush_handlers()`
+        // aren't pending anymore.
+        let notified = context::take_notified(&self.lua);
+        let failed = task_results.values().any(|o| o.result.errored());
+        let mut handler_results = Vec::new();
+        if !notified.is_empty() {
+            if failed && !opts.force_handlers {
+                output!(
+                    "{}",
+                    style(format!(
+                        "Skipping {} notified handler(s) after failure, use --force-handlers to run them",
+                        notified.len()
+                    ))
+                    .yellow()
+                );
+            } else {
+                handler_results = self.run_handlers(notified)?;
+                for (name, outcome) in handler_results.iter() {
+                    incomplete |= outcome.result.incomplete();
+                    recap.push(outcome.recap(handler_description(name)));
+                }
+            }
+        }
+
+        if !opts.check {
+            let already_done: Vec<Task> = already_done
+                .iter()
+                .map(|&t| self.registry.task_for_handle(t))
+                .collect();
+            let succeeded: Vec<Task> = ordering
+                .iter()
+                .filter(|t| {
+                    task_results.get(t).is_some_and(|o| {
+                        matches!(o.result, TaskResult::Changed | TaskResult::Unchanged)
+                    })
+                })
+                .map(|&t| self.registry.task_for_handle(t))
+                .collect();
+            LastRun::after_run(target_keys, last_run.as_ref(), &already_done, &succeeded).save()?;
+        }
+
+        let end = Utc::now();
+        let report = RunReport {
+            success: !incomplete,
+            check: opts.check,
+            targets,
+            ordering: ordering.iter().map(|&t| self.task_label(t)).collect(),
+            start,
+            end,
+            duration: (end - start).as_seconds_f64(),
+            tasks: ordering
+                .iter()
+                .filter_map(|&t| {
+                    let outcome = task_results.get(&t)?;
+                    let description = self.registry.task_for_handle(t).description;
+                    Some(TaskReport::new(
+                        description,
+                        self.registry.name_for_handle(t),
+                        outcome,
+                    ))
+                })
+                .collect(),
+            handlers: handler_results
+                .iter()
+                .map(|(name, outcome)| {
+                    TaskReport::new(handler_description(name), Some(name.clone()), outcome)
+                })
+                .collect(),
+        };
+        callbacks::run_callbacks(&self.lua, &report)?;
+
+        if incomplete {
+            tracker::tracker().finish_fail(recap);
+        } else {
+            tracker::tracker().finish_success(recap);
+        }
+        Ok(Some(report))
+    }
+
+    /// Split off the tasks that don't need to run: those before `start_at` in
+    /// the ordering, and those that completed in the last run when resuming.
+    /// Returns the tasks already done and the tasks left to run, both in order.
+    fn split_completed(
+        &self,
+        ordering: Vec<TaskHandle>,
+        start_at: Option<&str>,
+        last_run: Option<&LastRun>,
+    ) -> Result<(Vec<TaskHandle>, Vec<TaskHandle>), TaskError> {
+        let start_idx = match start_at {
+            Some(name) => {
+                let task = self
+                    .registry
+                    .task_for_name(name)
+                    .ok_or_else(|| TaskError::Action(format!("Unknown task {}", name)))?;
+                ordering.iter().position(|&t| t == task.id).ok_or_else(|| {
+                    TaskError::Action(format!("Task {} is not part of this run", name))
+                })?
+            }
+            None => 0,
+        };
+        let (done, rest): (Vec<_>, Vec<_>) =
+            ordering.into_iter().enumerate().partition(|&(idx, t)| {
+                idx < start_idx
+                    || last_run
+                        .is_some_and(|last| last.is_completed(&self.registry.task_for_handle(t)))
+            });
+        Ok((
+            done.into_iter().map(|(_, t)| t).collect(),
+            rest.into_iter().map(|(_, t)| t).collect(),
+        ))
+    }
+
+    /// A task's global name, or its description if it has none.
+    fn task_label(&self, task: TaskHandle) -> String {
+        self.registry
+            .name_for_handle(task)
+            .unwrap_or_else(|| self.registry.task_for_handle(task).description)
+    }
+
+    /// Summarize the tasks that ran, in execution order.
+    fn recap(
+        &self,
+        ordering: &[TaskHandle],
+        task_results: &HashMap<TaskHandle, TaskOutcome>,
+    ) -> Vec<TaskRecap> {
+        ordering
+            .iter()
+            .filter_map(|&task| {
+                let outcome = task_results.get(&task)?;
+                let t = self.registry.task_for_handle(task);
+                Some(outcome.recap(t.description))
+            })
+            .collect()
+    }
+
+    /// Run the notified handlers in definition order, including any notified
+    /// by other handlers. Stops at the first handler that fails. Returns each
+    /// handler's name with its outcome.
+    fn run_handlers(
+        &self,
+        mut pending: Vec<String>,
+    ) -> Result<Vec<(String, TaskOutcome)>, TaskError> {
+        let defined = handlers::defined_handlers(&self.lua)?;
+        let mut results = Vec::new();
+        tracker::tracker().run(pending.len());
+        output!("{}", style("Handlers").yellow());
+        while !pending.is_empty() {
+            let next = defined
+                .iter()
+                .find(|(name, _)| pending.contains(name))
+                .cloned();
+            let Some((name, f)) = next else {
+                break;
+            };
+            pending.retain(|p| *p != name);
+            let tracked = TrackedTask {
+                id: format!("handler:{}", name),
+                description: handler_description(&name),
+            };
+            tracker::tracker().task(tracked.clone());
+            output!("Handler [ {} ]", style(&name).cyan());
+            let outcome = self.run_function(f, None)?;
+            self.finish_task(tracked, &outcome.result);
+            for h in context::take_notified(&self.lua) {
+                if !pending.contains(&h) {
+                    pending.push(h);
+                    tracker::tracker().run(1);
+                }
+            }
+            let failed = outcome.result.errored();
+            results.push((name, outcome));
+            if failed {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Whether all parents of a task have run successfully.
+    /// `None` means at least one parent has not finished yet. Parents that
+    /// aren't in `ordering` were done before this run and count as succeeded.
+    fn parents_succeeded(
+        &self,
+        task: TaskHandle,
+        ordering: &[TaskHandle],
+        task_results: &HashMap<TaskHandle, TaskOutcome>,
+    ) -> Option<bool> {
+        let mut ready = true;
+        for parent in self.graph.direct_parents(task) {
+            match task_results.get(&parent) {
+                Some(o) if o.result.succeeded() => {}
+                Some(_) => return Some(false),
+                None if !ordering.contains(&parent) => {}
+                None => ready = false,
+            }
+        }
+        ready.then_some(true)
+    }
+
+    fn start_task(&self, task: TaskHandle) -> TrackedTask {
+        let t = self.registry.task_for_handle(task);
+        let tracked = TrackedTask {
+            id: format!("task:{}", task),
+            description: t.description,
+        };
+        tracker::tracker().task(tracked.clone());
+        output!("Task [ {} ]", style(&tracked.description).cyan());
+        tracked
+    }
+
+    fn finish_task(&self, task: TrackedTask, result: &TaskResult) {
+        match result {
+            TaskResult::Changed | TaskResult::Unchanged => tracker::tracker().task_success(task),
+            TaskResult::Incomplete(IncompleteReason::Failed) => tracker::tracker().task_fail(task),
+            TaskResult::NotApplicable(_) | TaskResult::Incomplete(_) => {
+                tracker::tracker().task_skip(task)
+            }
+        }
+    }
+
+    /// Ask whether to run a task, for `--step`.
+    fn step(&self, task: TaskHandle) -> StepDecision {
+        let prompt = StepPrompt {
+            task: self.registry.task_for_handle(task).description,
+            deps: self
+                .graph
+                .direct_parents(task)
+                .into_iter()
+                .map(|t| self.registry.task_for_handle(t).description)
+                .collect(),
+        };
+        tracker::prompt_step(&prompt)
+    }
+
+    /// The task's `when` condition, if it has one.
+    fn condition(&self, task: TaskHandle) -> Result<Option<Function>, TaskError> {
+        let conditions: Table = self.lua.named_registry_value("task_conditions")?;
+        Ok(conditions.get(task.0)?)
+    }
+
+    /// Evaluate the task's `when` condition. Returns why the task doesn't
+    /// apply if the condition is false, or the failed outcome if it errored.
+    fn check_condition(&self, task: TaskHandle) -> Result<Option<TaskOutcome>, TaskError> {
+        let Some(when) = self.condition(task)? else {
+            return Ok(None);
+        };
+        let outcome = match when.call::<(bool, Option<String>)>(()) {
+            Ok((true, _)) => return Ok(None),
+            Ok((false, reason)) => {
+                let reason = reason.unwrap_or_else(|| "when condition is false".to_string());
+                indent_output!(
+                    1,
+                    "{}",
+                    style(format!("Not applicable: {}", reason)).yellow()
+                );
+                TaskOutcome::new(TaskResult::NotApplicable(reason))
+            }
+            Err(e) => {
+                output!("Error in when condition: {}", e);
+                let mut outcome =
+                    TaskOutcome::new(TaskResult::Incomplete(IncompleteReason::Failed));
+                outcome.error = Some(format!("Error in when condition: {}", e));
+                outcome
+            }
+        };
+        Ok(Some(outcome))
+    }
+
+    /// Run a single task body in this Lua state.
+    fn run_task(&self, task: TaskHandle) -> Result<TaskOutcome, TaskError> {
+        if let Some(outcome) = self.check_condition(task)? {
+            return Ok(outcome);
+        }
+        let task_table: Table = self.lua.named_registry_value("tasks")?;
+        let maybe_f: Option<Function> = task_table.get(task.0)?;
+        let Some(f) = maybe_f else {
+            return Ok(TaskOutcome::new(TaskResult::Unchanged));
+        };
+        let options = self.registry.options_for_handle(task);
+        let mut delay = options.retry_delay;
+        let mut outcome = self.run_function(f.clone(), options.timeout)?;
+        for attempt in 1..=options.retries {
+            if !outcome.result.errored() {
+                break;
+            }
+            output!(
+                "{}",
+                style(format!(
+                    "Retrying in {:.1}s (attempt {} of {})",
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    options.retries + 1
+                ))
+                .yellow()
+            );
+            std::thread::sleep(delay);
+            delay = delay.mul_f64(options.backoff);
+            let retry = self.run_function(f.clone(), options.timeout)?;
+            // Earlier attempts may have changed things before failing
+            outcome.changes += retry.changes;
+            for f in retry.files {
+                if !outcome.files.contains(&f) {
+                    outcome.files.push(f);
+                }
+            }
+            let start = outcome.timing.map(|t| t.start);
+            outcome = TaskOutcome {
+                timing: retry.timing.map(|t| Timing {
+                    start: start.unwrap_or(t.start),
+                    end: t.end,
+                }),
+                changes: outcome.changes,
+                files: outcome.files,
+                ..retry
+            };
+        }
+        Ok(outcome)
+    }
+
+    /// Call a task or handler body, collecting its result, changes and notifications.
+    /// With a timeout, the body is aborted once it has run that long.
+    fn run_function(
+        &self,
+        f: Function,
+        timeout: Option<Duration>,
+    ) -> Result<TaskOutcome, TaskError> {
+        let deadline = timeout.map(|t| (Instant::now() + t, t));
+        if let Some((deadline, t)) = deadline {
+            context::set_deadline(&self.lua, Some(deadline));
+            // Commands started by the task watch the deadline themselves, this
+            // catches Lua code that runs too long.
+            self.lua.set_hook(
+                HookTriggers::new().every_nth_instruction(1000),
+                move |_, _| {
+                    if Instant::now() >= deadline {
+                        Err(mlua::Error::external(TaskError::Timeout(t)))
+                    } else {
+                        Ok(VmState::Continue)
                     }
+                },
+            );
+        }
+        let start = Utc::now();
+        let called = f.call(());
+        if deadline.is_some() {
+            self.lua.remove_hook();
+            context::set_deadline(&self.lua, None);
+        }
+        let timing = Timing {
+            start,
+            end: Utc::now(),
+        };
+        let changes = context::take_changes(&self.lua);
+        let files = context::take_changed_files(&self.lua);
+        let mut error = None;
+        let mut traceback = None;
+        let mut output = None;
+        let result = match called {
+            Ok(mlua::Value::UserData(ud)) => {
+                if ud.is::<TaskResult>() {
+                    let tr: &TaskResult = &ud.borrow().unwrap();
+                    tr.clone()
+                } else {
+                    TaskResult::Unchanged
+                }
+            }
+            // An output that can't be passed on fails the task, not the run
+            Ok(mlua::Value::Table(t)) => match util::lua_table_to_json(t) {
+                Ok(o) => {
+                    output = Some(o);
+                    TaskResult::Unchanged
+                }
+                Err(e) => {
+                    let msg = format!("Task returned a table that can't be used as output: {}", e);
+                    output!("{}", msg);
+                    error = Some(msg);
+                    TaskResult::Incomplete(IncompleteReason::Failed)
+                }
+            },
+            Ok(_) => TaskResult::Unchanged,
+            Err(mlua::Error::CallbackError {
+                traceback: tb,
+                cause,
+            }) => {
+                if let mlua::Error::ExternalError(ref e) = *cause.clone() {
+                    output!("{}\n{}", e, tb);
+                    output!("Source: {:?}", e.source());
+                    error = Some(e.to_string());
+                } else {
+                    output!("{}\n{}", cause, tb);
+                    error = Some(cause.to_string());
                 }
+                traceback = Some(tb);
+                TaskResult::Incomplete(IncompleteReason::Failed)
             }
-            // If a parent hasn't been run, we also need to skip
-            if parent_failed {
-                task_results.insert(task, TaskResult::Incomplete(IncompleteReason::Skipped));
+            // Errors raised from Lua, e.g. with `error()`, carry the traceback
+            // in the message.
+            Err(mlua::Error::RuntimeError(msg)) => {
+                output!("{}", msg);
+                let (msg, tb) = match msg.split_once("\nstack traceback:") {
+                    Some((m, tb)) => (m.to_string(), Some(format!("stack traceback:{}", tb))),
+                    None => (msg, None),
+                };
+                error = Some(msg);
+                traceback = tb;
+                TaskResult::Incomplete(IncompleteReason::Failed)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let result = match result {
+            TaskResult::Unchanged if changes.changed > 0 => TaskResult::Changed,
+            r => r,
+        };
+        if let Some((deadline, t)) = deadline {
+            if result.errored() && Instant::now() >= deadline {
+                error = Some(TaskError::Timeout(t).to_string());
+            }
+        }
+        Ok(TaskOutcome {
+            result,
+            changes,
+            files,
+            notified: Vec::new(),
+            error,
+            traceback,
+            timing: Some(timing),
+            output,
+        })
+    }
+
+    /// Make a finished task's output available to the tasks that run after it.
+    fn set_output(&self, task: TaskHandle, output: &serde_json::Value) -> Result<(), TaskError> {
+        let outputs: Table = self.lua.named_registry_value("task_outputs")?;
+        outputs.set(task.0, util::json_to_lua_value(&self.lua, output)?)?;
+        Ok(())
+    }
+
+    fn execute_sequential(
+        &self,
+        ordering: &[TaskHandle],
+        opts: &ExecOptions,
+    ) -> Result<HashMap<TaskHandle, TaskOutcome>, TaskError> {
+        let mut task_results: HashMap<TaskHandle, TaskOutcome> = HashMap::new();
+        for &task in ordering {
+            let tracked = self.start_task(task);
+
+            // Did all our parents run successfully? Parents are guaranteed to
+            // have finished due to ordering.
+            if self.parents_succeeded(task, ordering, &task_results) != Some(true) {
+                let outcome = TaskOutcome::skipped();
+                self.finish_task(tracked, &outcome.result);
+                task_results.insert(task, outcome);
                 continue;
             }
 
-            let maybe_f: Option<Function> = task_table.get(task.0)?;
-            if let Some(f) = maybe_f {
-                match f.call(()) {
-                    Ok(mlua::Value::UserData(ud)) => {
-                        if ud.is::<TaskResult>() {
-                            let tr: &TaskResult = &ud.borrow().unwrap();
-                            if let TaskResult::Incomplete(_) = tr {
-                                tracker::tracker().task_skip();
+            let outcome = match opts.step.then(|| self.step(task)) {
+                Some(StepDecision::Skip) => {
+                    TaskOutcome::new(TaskResult::NotApplicable("skipped with --step".to_string()))
+                }
+                Some(StepDecision::Abort) => {
+                    let outcome = TaskOutcome::new(TaskResult::Incomplete(
+                        IncompleteReason::Cancelled(Some("aborted with --step".to_string())),
+                    ));
+                    self.finish_task(tracked, &outcome.result);
+                    task_results.insert(task, outcome);
+                    break;
+                }
+                _ => self.run_task(task)?,
+            };
+            self.finish_task(tracked, &outcome.result);
+            if let Some(output) = &outcome.output {
+                self.set_output(task, output)?;
+            }
+            let failed = outcome.result.errored();
+            task_results.insert(task, outcome);
+            // With --keep-going, dependents of the failed task are skipped
+            // by the parent check above.
+            if failed && !opts.keep_going {
+                break;
+            }
+        }
+        Ok(task_results)
+    }
+
+    /// Run tasks on `jobs` worker threads, dispatching each task as soon as all of
+    /// its parents have succeeded.
+    ///
+    /// Lua states can't be shared between threads, so every worker evaluates the
+    /// config into its own state. Tasks are matched across states by handle, so
+    /// nothing runs until every worker has checked that it defined the same tasks
+    /// as this state.
+    fn execute_parallel(
+        &self,
+        ordering: &[TaskHandle],
+        opts: &ExecOptions,
+    ) -> Result<HashMap<TaskHandle, TaskOutcome>, TaskError> {
+        let jobs = opts.jobs;
+        let mut task_results: HashMap<TaskHandle, TaskOutcome> = HashMap::new();
+        // Tasks are sent with the outputs of the tasks that finished before
+        // them, and the handlers pending so far for `flush_handlers()`
+        let (work_tx, work_rx) = channel::unbounded::<Work>();
+        let (done_tx, done_rx) =
+            channel::unbounded::<(TaskHandle, Result<TaskOutcome, TaskError>)>();
+
+        let (ready_tx, ready_rx) = channel::unbounded::<Result<(), TaskError>>();
+
+        std::thread::scope(|s| {
+            let workers = jobs.min(ordering.len());
+            for _ in 0..workers {
+                let channels = (ready_tx.clone(), work_rx.clone(), done_tx.clone());
+                let (builtins, src, vars) = (&self.builtins, &self.src, &self.vars);
+                let registry = &self.registry;
+                s.spawn(move || worker(builtins, src, vars, registry, opts, channels));
+            }
+            drop(done_tx);
+            for ready in ready_rx.iter().take(workers) {
+                if let Err(e) = ready {
+                    // Closing the work queue lets the other workers exit
+                    drop(work_tx);
+                    return Err(e);
+                }
+            }
+
+            let mut pending: Vec<TaskHandle> = ordering.to_vec();
+            let mut running: HashMap<TaskHandle, TrackedTask> = HashMap::new();
+            let mut error = None;
+            let mut failed = false;
+            loop {
+                if !failed || opts.keep_going {
+                    // Pending is in dependency order, so one pass is enough to
+                    // propagate skips down a chain of dependents.
+                    let mut i = 0;
+                    while i < pending.len() && running.len() < jobs {
+                        let task = pending[i];
+                        match self.parents_succeeded(task, ordering, &task_results) {
+                            Some(true) => {
+                                pending.remove(i);
+                                let tracked = self.start_task(task);
+                                running.insert(task, tracked);
+                                let outputs = task_results
+                                    .iter()
+                                    .filter_map(|(&t, o)| o.output.clone().map(|o| (t, o)))
+                                    .collect();
+                                let notified = context::take_notified(&self.lua);
+                                let _ = work_tx.send((task, outputs, notified));
                             }
-                            task_results.insert(task, tr.clone());
-                        } else {
-                            task_results.insert(task, TaskResult::Success);
+                            Some(false) => {
+                                pending.remove(i);
+                                let tracked = self.start_task(task);
+                                let outcome = TaskOutcome::skipped();
+                                self.finish_task(tracked, &outcome.result);
+                                task_results.insert(task, outcome);
+                            }
+                            None => i += 1,
                         }
                     }
-                    Ok(_) => {
-                        tracker::tracker().task_success();
-                        task_results.insert(task, TaskResult::Success);
-                    }
-                    Err(mlua::Error::CallbackError { traceback, cause }) => {
-                        if let mlua::Error::ExternalError(ref e) = *cause.clone() {
-                            output!("{}\n{}", e, traceback);
-                            output!("Source: {:?}", e.source())
-                        } else {
-                            output!("{}\n{}", cause, traceback);
+                }
+                if running.is_empty() {
+                    break;
+                }
+                let (task, res) = match done_rx.recv() {
+                    Ok(r) => r,
+                    Err(_) => break,
+                };
+                let tracked = running.remove(&task).unwrap_or_default();
+                match res {
+                    Ok(outcome) => {
+                        self.finish_task(tracked, &outcome.result);
+                        for h in outcome.notified.iter() {
+                            context::notify(&self.lua, h);
+                        }
+                        // Handlers run in this state once all tasks are done
+                        if let Some(output) = &outcome.output {
+                            if let Err(e) = self.set_output(task, output) {
+                                error.get_or_insert(e);
+                            }
                         }
-                        tracker::tracker().task_fail();
-                        task_results.insert(task, TaskResult::Incomplete(IncompleteReason::Failed));
-                        break;
+                        failed |= outcome.result.errored();
+                        task_results.insert(task, outcome);
+                    }
+                    Err(e) => {
+                        let mut outcome =
+                            TaskOutcome::new(TaskResult::Incomplete(IncompleteReason::Failed));
+                        outcome.error = Some(e.to_string());
+                        self.finish_task(tracked, &outcome.result);
+                        task_results.insert(task, outcome);
+                        failed = true;
+                        error.get_or_insert(e);
                     }
-                    Err(e) => return Err(e.into()),
                 }
-            } else {
-                task_results.insert(task, TaskResult::Success);
             }
+            // Closing the work queue lets the workers exit
+            drop(work_tx);
+            match error {
+                Some(e) => Err(e),
+                None => Ok(task_results),
+            }
+        })
+    }
+}
+
+/// Outputs of finished tasks, sent to workers with each task.
+type Outputs = Vec<(TaskHandle, serde_json::Value)>;
+
+/// A task for a worker, with the outputs and pending handlers it should see.
+type Work = (TaskHandle, Outputs, Vec<String>);
+
+/// Where a worker says whether it's ready, gets tasks, and sends their outcomes.
+type WorkerChannels = (
+    channel::Sender<Result<(), TaskError>>,
+    channel::Receiver<Work>,
+    channel::Sender<(TaskHandle, Result<TaskOutcome, TaskError>)>,
+);
+
+fn handler_description(name: &str) -> String {
+    format!("{} (handler)", name)
+}
+
+fn worker(
+    builtins: &[Builtin],
+    src: &str,
+    vars: &Variables,
+    registry: &TaskRegistry,
+    opts: &ExecOptions,
+    (ready, work, done): WorkerChannels,
+) {
+    // Anything the config prints was printed when it was first evaluated
+    let state = tracker::quietly(|| {
+        LuaState::with_builtins(builtins).and_then(|s| s.eval(src, vars.clone()))
+    });
+    let state = match state {
+        Ok(s) if s.registry.same_tasks(registry) => s,
+        Ok(_) => {
+            let _ = ready.send(Err(TaskError::Action(
+                "The config defined different tasks when it was evaluated again, so it can't \
+                 run with --jobs. Tasks defined in a pairs() loop are a common cause, since \
+                 the order changes each time; loop over sorted keys instead."
+                    .to_string(),
+            )));
+            return;
         }
-        if task_results.into_values().any(|r| r.incomplete()) {
-            tracker::tracker().finish_fail();
-            return Err(TaskError::SkippedTask);
+        Err(e) => {
+            let _ = ready.send(Err(TaskError::Action(format!(
+                "Failed to initialize worker: {}",
+                e
+            ))));
+            return;
         }
-        tracker::tracker().finish_success();
-        Ok(())
+    };
+    let _ = ready.send(Ok(()));
+    opts.run_context().install(&state.lua);
+    for (task, outputs, notified) in work.iter() {
+        for h in notified.iter() {
+            context::notify(&state.lua, h);
+        }
+        let res = outputs
+            .iter()
+            .try_for_each(|(t, o)| state.set_output(*t, o))
+            .and_then(|_| state.run_task(task))
+            .map(|mut outcome| {
+                outcome.notified = context::take_notified(&state.lua);
+                outcome
+            });
+        let _ = done.send((task, res));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{report::TaskStatus, *};
+
+    fn evaluated(src: &str) -> EvaluatedLuaState {
+        crate::tracker::init_for_tests();
+        LuaState::new()
+            .and_then(|s| s.eval(src, Variables::default()))
+            .unwrap()
+    }
+
+    fn handles(state: &EvaluatedLuaState, names: &[&str]) -> Vec<TaskHandle> {
+        names
+            .iter()
+            .map(|n| state.registry.task_for_name(n).unwrap().id)
+            .collect()
+    }
+
+    /// Options for a check run, which doesn't record anything for --resume.
+    fn check_run(jobs: usize) -> ExecOptions {
+        ExecOptions {
+            run_defaults: false,
+            show_plan: false,
+            jobs,
+            check: true,
+            diff: false,
+            force_handlers: false,
+            keep_going: false,
+            start_at: None,
+            resume: false,
+            tags: Vec::new(),
+            skip_tags: Vec::new(),
+            step: false,
+        }
+    }
+
+    fn statuses(report: &RunReport) -> Vec<(&str, &TaskStatus)> {
+        report
+            .tasks
+            .iter()
+            .map(|t| (t.task.as_str(), &t.status))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_runs_tasks_once_with_their_parents_outputs() {
+        let state = evaluated(
+            r#"
+            local base = task("base", function() return { n = 1 } end)
+            local left = task("left", { base }, function() return { n = outputs.base.n + 1 } end)
+            local right = task("right", { base }, function() return { n = outputs.base.n + 2 } end)
+            local top = task("top", { left, right }, function()
+                return { n = outputs.left.n + outputs.right.n }
+            end)
+            export { base = base, left = left, right = right, top = top }
+            "#,
+        );
+        let report = state.execute(&["top"], &check_run(4)).unwrap().unwrap();
+        assert!(report.success);
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("base", &TaskStatus::Success),
+                ("left", &TaskStatus::Success),
+                ("right", &TaskStatus::Success),
+                ("top", &TaskStatus::Success),
+            ]
+        );
+        let top = report.tasks.last().unwrap().output.as_ref().unwrap();
+        assert_eq!(top["n"].as_f64(), Some(5.0));
+    }
+
+    #[test]
+    fn parallel_keeps_going_past_failed_branches() {
+        let state = evaluated(
+            r#"
+            local bad = task("bad", function() error("boom") end)
+            local after = task("after", { bad }, function() end)
+            local other = task("other", function() end)
+            export { after = after, other = other }
+            "#,
+        );
+        let opts = ExecOptions {
+            keep_going: true,
+            ..check_run(2)
+        };
+        let report = state.execute(&["after", "other"], &opts).unwrap().unwrap();
+        assert!(!report.success);
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("bad", &TaskStatus::Failed),
+                ("after", &TaskStatus::Skipped),
+                ("other", &TaskStatus::Success),
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel_refuses_configs_that_define_tasks_differently() {
+        // math.random is seeded differently in every Lua state
+        let state = evaluated(
+            r#"
+            local t = task("task " .. math.random(1, 1 << 40), function() end)
+            export { t = t }
+            "#,
+        );
+        let err = state.execute(&["t"], &check_run(2)).unwrap_err();
+        assert!(err.to_string().contains("defined different tasks"));
+    }
+
+    #[test]
+    fn parallel_refuses_configs_with_top_level_actions() {
+        crate::tracker::init_for_tests();
+        let mut state = LuaState::new().unwrap();
+        state.register_fn(crate::actions::shell).unwrap();
+        let state = state
+            .eval(
+                r#"
+                shell("true", { check_safe = true })
+                local t = task("t", function() end)
+                export { t = t }
+                "#,
+                Variables::default(),
+            )
+            .unwrap();
+        let err = state.execute(&["t"], &check_run(2)).unwrap_err();
+        assert!(err.to_string().contains("outside of tasks"));
+        assert!(state.execute(&["t"], &check_run(1)).is_ok());
+    }
+
+    const CHAIN: &str = r#"
+        local a = task("a", function() end)
+        local b = task("b", { a }, function() end)
+        local c = task("c", { b }, function() end)
+        export { a = a, b = b, c = c }
+    "#;
+
+    #[test]
+    fn start_at_splits_off_earlier_tasks() {
+        let state = evaluated(CHAIN);
+        let ordering = state.execution_ordering(&handles(&state, &["c"]));
+        let (done, rest) = state.split_completed(ordering, Some("b"), None).unwrap();
+        assert_eq!(done, handles(&state, &["a"]));
+        assert_eq!(rest, handles(&state, &["b", "c"]));
+    }
+
+    #[test]
+    fn resume_splits_off_completed_tasks() {
+        let state = evaluated(CHAIN);
+        let ordering = state.execution_ordering(&handles(&state, &["c"]));
+        let last = LastRun {
+            targets: Vec::new(),
+            completed: vec![TaskKey::new(&state.registry.task_for_name("a").unwrap())],
+        };
+        let (done, rest) = state.split_completed(ordering, None, Some(&last)).unwrap();
+        assert_eq!(done, handles(&state, &["a"]));
+        assert_eq!(rest, handles(&state, &["b", "c"]));
+    }
+
+    #[test]
+    fn resume_matches_unnamed_tasks() {
+        let state = evaluated(
+            r#"
+            local setup = task("setup", function() end)
+            local c = task("c", { setup }, function() end)
+            export { c = c }
+            "#,
+        );
+        let ordering = state.execution_ordering(&handles(&state, &["c"]));
+        let setup = state.registry.task_for_handle(ordering[0]);
+        assert_eq!(setup.description(), "setup");
+        let last = LastRun {
+            targets: Vec::new(),
+            completed: vec![TaskKey::new(&setup)],
+        };
+        let (done, rest) = state.split_completed(ordering, None, Some(&last)).unwrap();
+        assert_eq!(done, vec![setup.id]);
+        assert_eq!(rest, handles(&state, &["c"]));
+    }
+
+    #[test]
+    fn start_at_must_be_part_of_the_run() {
+        let state = evaluated(CHAIN);
+        let ordering = state.execution_ordering(&handles(&state, &["b"]));
+        let err = state
+            .split_completed(ordering, Some("c"), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("Task c is not part of this run"));
+    }
+
+    #[test]
+    fn config_errors_report_declared_problems() {
+        crate::tracker::init_for_tests();
+        let src = r#"
+            declare_vars { hostname = { required = true }, port = { type = "integer" } }
+            local name = vars.hostname
+        "#;
+        let vars = Variables::from_json(serde_json::json!({"port": "80"}));
+        let err = LuaState::new()
+            .and_then(|s| s.eval(src, vars))
+            .err()
+            .unwrap();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("'hostname' is required but not set"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("'port' must be an integer"), "{}", msg);
     }
 }
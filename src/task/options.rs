@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use mlua::{Table, Value};
+
+/// Options given to `task()` in the named fields of its dependency table,
+/// e.g. `task("fetch", { setup, retries = 3 }, function() ... end)`.
+#[derive(Debug, Clone)]
+pub struct TaskOptions {
+    /// How many more times to run the task after it fails.
+    pub retries: u32,
+    /// How long to wait before the first retry.
+    pub retry_delay: Duration,
+    /// Factor the delay is multiplied by after every retry.
+    pub backoff: f64,
+    /// Abort an attempt that runs for longer than this.
+    pub timeout: Option<Duration>,
+    /// Tags for selecting the task with `--tags` and `--skip-tags`.
+    pub tags: Vec<String>,
+}
+
+impl Default for TaskOptions {
+    fn default() -> Self {
+        TaskOptions {
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            backoff: 1.0,
+            timeout: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+fn seconds(key: &str, value: Value) -> mlua::Result<Duration> {
+    let secs = match value {
+        Value::Integer(i) => i as f64,
+        Value::Number(n) => n,
+        _ => {
+            return Err(mlua::Error::runtime(format!(
+                "Task option '{}' must be a number of seconds",
+                key
+            )))
+        }
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| {
+        mlua::Error::runtime(format!(
+            "Task option '{}' must be a positive number of seconds",
+            key
+        ))
+    })
+}
+
+impl TaskOptions {
+    /// Read the named fields of `table`. The sequence part (the task's
+    /// dependencies) is ignored.
+    pub fn from_table(table: &Table) -> mlua::Result<TaskOptions> {
+        let mut opts = TaskOptions::default();
+        for pair in table.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = match key {
+                Value::Integer(_) => continue,
+                Value::String(s) => s.to_str()?.to_string(),
+                _ => return Err(mlua::Error::runtime("Invalid key in task options")),
+            };
+            match key.as_str() {
+                "retries" => match value {
+                    Value::Integer(i) if i >= 0 => opts.retries = i as u32,
+                    _ => {
+                        return Err(mlua::Error::runtime(
+                            "Task option 'retries' must be a non-negative integer",
+                        ))
+                    }
+                },
+                "retry_delay" => opts.retry_delay = seconds(&key, value)?,
+                "backoff" => match value {
+                    Value::Integer(i) if i >= 1 => opts.backoff = i as f64,
+                    Value::Number(n) if n >= 1.0 => opts.backoff = n,
+                    _ => {
+                        return Err(mlua::Error::runtime(
+                            "Task option 'backoff' must be a number of at least 1",
+                        ))
+                    }
+                },
+                "timeout" => opts.timeout = Some(seconds(&key, value)?),
+                // Functions live in the Lua registry with the task body
+                "when" => match value {
+                    Value::Function(_) => {}
+                    _ => {
+                        return Err(mlua::Error::runtime(
+                            "Task option 'when' must be a function",
+                        ))
+                    }
+                },
+                "tags" => {
+                    opts.tags = match value {
+                        Value::String(s) => vec![s.to_str()?.to_string()],
+                        Value::Table(t) => t.sequence_values().collect::<mlua::Result<_>>()?,
+                        _ => {
+                            return Err(mlua::Error::runtime(
+                                "Task option 'tags' must be a string or list of strings",
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    return Err(mlua::Error::runtime(format!(
+                        "Unknown task option '{}'",
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(opts)
+    }
+}
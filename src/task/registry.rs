@@ -5,13 +5,14 @@ use std::{
 
 use crate::debug_output;
 
-use super::{Task, TaskHandle};
+use super::{options::TaskOptions, Task, TaskHandle};
 
 #[derive(Debug, Clone)]
 pub struct TaskRegistry {
     next_id: Arc<AtomicUsize>,
     named: Arc<RwLock<HashMap<String, TaskHandle>>>,
     tasks: Arc<RwLock<HashMap<TaskHandle, Task>>>,
+    options: Arc<RwLock<HashMap<TaskHandle, TaskOptions>>>,
 }
 
 impl TaskRegistry {
@@ -20,6 +21,7 @@ impl TaskRegistry {
             next_id: Arc::new(AtomicUsize::new(1)),
             named: Arc::new(RwLock::new(HashMap::new())),
             tasks: Arc::new(RwLock::new(HashMap::new())),
+            options: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -28,6 +30,48 @@ impl TaskRegistry {
         tasks.insert(task.id, task);
     }
 
+    pub fn register_options(&self, id: TaskHandle, options: TaskOptions) {
+        let mut opts = self.options.write().unwrap();
+        opts.insert(id, options);
+    }
+
+    pub fn options_for_handle(&self, id: TaskHandle) -> TaskOptions {
+        self.options
+            .read()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Tasks with any of the given tags, in definition order.
+    pub fn tasks_tagged(&self, tags: &[String]) -> Vec<TaskHandle> {
+        let mut tagged: Vec<TaskHandle> = self
+            .options
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, o)| o.tags.iter().any(|t| tags.contains(t)))
+            .map(|(&id, _)| id)
+            .collect();
+        tagged.sort();
+        tagged
+    }
+
+    /// Every tag used by a task, sorted.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .options
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|o| o.tags.iter().cloned())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
     pub fn register_name<S: Into<String>>(&self, id: TaskHandle, name: S) {
         let mut named = self.named.write().unwrap();
         let name = name.into();
@@ -49,6 +93,19 @@ impl TaskRegistry {
         }
     }
 
+    /// The global name a task was assigned to, if any. A task assigned to
+    /// several globals gets the first name alphabetically.
+    pub fn name_for_handle(&self, id: TaskHandle) -> Option<String> {
+        self.named
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, &h)| h == id)
+            .map(|(name, _)| name)
+            .min()
+            .cloned()
+    }
+
     pub fn tasks(&self) -> Vec<Task> {
         self.tasks.read().unwrap().values().cloned().collect()
     }
@@ -63,6 +120,16 @@ impl TaskRegistry {
             .collect()
     }
 
+    /// Whether both registries have the same tasks, with the same handles,
+    /// dependencies and names, e.g. from evaluating a config twice.
+    pub fn same_tasks(&self, other: &TaskRegistry) -> bool {
+        let mut tasks = self.tasks();
+        let mut other_tasks = other.tasks();
+        tasks.sort();
+        other_tasks.sort();
+        tasks == other_tasks && self.named_tasks() == other.named_tasks()
+    }
+
     pub fn next_id(&self) -> usize {
         self.next_id
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
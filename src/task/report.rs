@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::TaskError, secrets};
+
+use super::{context::ChangeCount, IncompleteReason, TaskOutcome, TaskResult};
+
+/// When a task body started and finished running.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timing {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Timing {
+    /// Wall-clock duration in seconds.
+    pub fn duration(&self) -> f64 {
+        (self.end - self.start).as_seconds_f64()
+    }
+}
+
+/// How a task ended, as written to the run report.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum TaskStatus {
+    Success,
+    /// Not run because its `when` condition was false.
+    #[serde(rename = "not_applicable")]
+    NotApplicable {
+        reason: String,
+    },
+    Cancelled {
+        reason: Option<String>,
+    },
+    Failed,
+    Skipped,
+    /// Never started, because an earlier task failed and stopped the run.
+    #[serde(rename = "not_run")]
+    NotRun,
+}
+
+impl From<&TaskResult> for TaskStatus {
+    fn from(result: &TaskResult) -> Self {
+        match result {
+            TaskResult::Changed | TaskResult::Unchanged => TaskStatus::Success,
+            TaskResult::NotApplicable(reason) => TaskStatus::NotApplicable {
+                reason: reason.clone(),
+            },
+            TaskResult::Incomplete(IncompleteReason::Cancelled(reason)) => TaskStatus::Cancelled {
+                reason: reason.clone(),
+            },
+            TaskResult::Incomplete(IncompleteReason::Failed) => TaskStatus::Failed,
+            TaskResult::Incomplete(IncompleteReason::Skipped) => TaskStatus::Skipped,
+            TaskResult::Incomplete(IncompleteReason::NotRun) => TaskStatus::NotRun,
+        }
+    }
+}
+
+/// One task (or handler) in the run report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskReport {
+    pub task: String,
+    /// Global name of the task in the config, if it has one.
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub status: TaskStatus,
+    pub error: Option<String>,
+    pub traceback: Option<String>,
+    /// Unset for tasks that never ran.
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub changes: ChangeCount,
+    /// Files and directories the task changed, or would have in check mode.
+    pub files: Vec<String>,
+    /// The table returned by the task body, if any.
+    pub output: Option<serde_json::Value>,
+}
+
+impl TaskReport {
+    pub fn new(task: String, name: Option<String>, outcome: &TaskOutcome) -> TaskReport {
+        TaskReport {
+            task,
+            name,
+            status: TaskStatus::from(&outcome.result),
+            error: outcome.error.clone().map(secrets::redact),
+            traceback: outcome.traceback.clone().map(secrets::redact),
+            start: outcome.timing.map(|t| t.start),
+            end: outcome.timing.map(|t| t.end),
+            duration: outcome.timing.map(|t| t.duration()),
+            changes: outcome.changes,
+            files: outcome.files.clone(),
+            output: outcome.output.clone().map(secrets::redact_json),
+        }
+    }
+}
+
+/// Machine-readable summary of a run, written by `--report`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunReport {
+    pub success: bool,
+    pub check: bool,
+    /// Requested targets, including defaults when `-D` is given.
+    pub targets: Vec<String>,
+    /// Every task in the run, in execution order.
+    pub ordering: Vec<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Duration in seconds.
+    pub duration: f64,
+    /// Every task in the plan, in execution order, including ones that
+    /// never ran.
+    pub tasks: Vec<TaskReport>,
+    /// Handlers that ran, in the order they ran.
+    pub handlers: Vec<TaskReport>,
+}
+
+impl RunReport {
+    /// Write the report to `path` as pretty-printed JSON.
+    pub fn save(&self, path: &Path) -> Result<(), TaskError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| TaskError::Action(format!("Couldn't serialize report: {}", e)))?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// `Err(TaskError::SkippedTask)` if any task or handler didn't complete.
+    pub fn result(&self) -> Result<(), TaskError> {
+        if self.success {
+            Ok(())
+        } else {
+            Err(TaskError::SkippedTask)
+        }
+    }
+}
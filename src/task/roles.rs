@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use mlua::{Function, Lua, MultiValue, Table, Value};
+
+use crate::{actions::util, debug_output, error::TaskError};
+
+use super::{declared, Variables};
+
+/// Where roles live, relative to the project directory.
+pub const ROLES_DIR: &str = "roles";
+
+/// The defaults of the role loaded by the `role`th call to `role()`. Every
+/// call has its own, so two roles, or one role loaded twice with different
+/// arguments, don't see each other's defaults.
+fn defaults_table(lua: &Lua, role: usize) -> Result<Option<Table>, mlua::Error> {
+    let roles: Option<Table> = lua.named_registry_value("roles")?;
+    match roles.map(|r| r.get::<Option<Table>>(role)).transpose()? {
+        Some(Some(r)) => r.get("defaults"),
+        _ => Ok(None),
+    }
+}
+
+/// A default of the given role, below variables given on the command line.
+pub(crate) fn role_default(
+    lua: &Lua,
+    role: usize,
+    key: &str,
+) -> Result<Option<Value>, mlua::Error> {
+    match defaults_table(lua, role)? {
+        Some(d) => d.get::<Option<Value>>(key),
+        None => Ok(None),
+    }
+}
+
+/// Every default of the given role.
+pub(crate) fn role_defaults(lua: &Lua, role: usize) -> Result<Vec<(String, Value)>, mlua::Error> {
+    match defaults_table(lua, role)? {
+        Some(d) => d.pairs::<String, Value>().collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Set a default for the given role, e.g. by assigning to `vars` in its
+/// `tasks.lua`.
+pub(crate) fn set_role_default(
+    lua: &Lua,
+    role: usize,
+    key: &str,
+    value: Value,
+) -> Result<(), mlua::Error> {
+    match defaults_table(lua, role)? {
+        Some(d) => d.set(key, value),
+        None => Err(mlua::Error::runtime(format!("Unknown role {}", role))),
+    }
+}
+
+/// The name of the given role.
+pub(crate) fn role_name(lua: &Lua, role: usize) -> Result<String, mlua::Error> {
+    let roles: Table = lua.named_registry_value("roles")?;
+    roles.get::<Table>(role)?.get("name")
+}
+
+/// Every role loaded so far, as the number `role_default()` takes and the
+/// role's name.
+pub(crate) fn loaded_roles(lua: &Lua) -> Result<Vec<(usize, String)>, mlua::Error> {
+    let roles: Option<Table> = lua.named_registry_value("roles")?;
+    let Some(roles) = roles else {
+        return Ok(Vec::new());
+    };
+    roles
+        .sequence_values::<Table>()
+        .enumerate()
+        .map(|(i, r)| Ok((i + 1, r?.get("name")?)))
+        .collect()
+}
+
+/// Read `defaults.json` from the role directory, if there is one, into new
+/// defaults for the role. `overrides` are set on top of them. Returns the
+/// number of the role, for `role_default()`.
+fn load_defaults(
+    lua: &Lua,
+    name: &str,
+    role_dir: &Path,
+    overrides: Option<Table>,
+) -> Result<usize, mlua::Error> {
+    let defaults = lua.create_table()?;
+    let path = role_dir.join("defaults.json");
+    if path.exists() {
+        let contents = util::read_file(&path).map_err(crate::error::io_error)?;
+        let json: serde_json::Value = serde_json::from_slice(&contents)
+            .map_err(|e| mlua::Error::runtime(format!("{}: {}", path.to_string_lossy(), e)))?;
+        let serde_json::Value::Object(map) = json else {
+            return Err(mlua::Error::runtime(format!(
+                "{}: Role defaults must be a JSON Object",
+                path.to_string_lossy()
+            )));
+        };
+        for (k, v) in map.iter() {
+            defaults.set(k.as_str(), util::json_to_lua_value(lua, v)?)?;
+        }
+    }
+    if let Some(overrides) = overrides {
+        for pair in overrides.pairs::<String, Value>() {
+            let (k, v) = pair?;
+            defaults.set(k, v)?;
+        }
+    }
+    let roles: Table = lua.named_registry_value("roles")?;
+    let role = lua.create_table()?;
+    role.set("name", name)?;
+    role.set("defaults", defaults)?;
+    roles.raw_push(role)?;
+    Ok(roles.raw_len())
+}
+
+/// Environment for the role's `tasks.lua`: the globals, except that `file()`
+/// and `dir()` resolve relative paths against the role directory, and `vars`
+/// and `declare_vars()` use the role's own defaults. Task bodies defined in
+/// the role keep this environment when they run later.
+fn role_env(lua: &Lua, role: usize, role_dir: &Path) -> Result<Table, mlua::Error> {
+    let env = lua.create_table()?;
+    let vars: mlua::AnyUserData = lua.globals().get("vars")?;
+    env.set("vars", vars.borrow::<Variables>()?.for_role(role))?;
+    let declare =
+        lua.create_function(move |ctx, specs: Table| declared::declare(ctx, specs, Some(role)))?;
+    env.set("declare_vars", declare)?;
+    for name in ["file", "dir"] {
+        let Some(f) = lua.globals().get::<Option<Function>>(name)? else {
+            continue;
+        };
+        let role_dir = role_dir.to_path_buf();
+        let wrapped = lua.create_function(move |_, path: String| {
+            // Absolute paths replace the role directory when joined
+            f.call::<Value>(role_dir.join(path).to_string_lossy().to_string())
+        })?;
+        env.set(name, wrapped)?;
+    }
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    meta.set("__newindex", lua.globals())?;
+    env.set_metatable(Some(meta));
+    Ok(env)
+}
+
+pub(crate) fn define_role_function(lua: &Lua) -> Result<(), TaskError> {
+    lua.set_named_registry_value("roles", lua.create_table()?)?;
+    let f = lua.create_function(|ctx, (name, vars): (String, Option<Table>)| {
+        let role_dir = PathBuf::from(ROLES_DIR).join(&name);
+        let tasks_file = role_dir.join("tasks.lua");
+        if !tasks_file.is_file() {
+            return Err(mlua::Error::runtime(format!(
+                "Unknown role '{}', {} not found",
+                name,
+                tasks_file.to_string_lossy()
+            )));
+        }
+        let role = load_defaults(ctx, &name, &role_dir, vars.clone())?;
+        let contents = util::read_file(&tasks_file).map_err(crate::error::io_error)?;
+        debug_output!("Loading role '{}'", name);
+        ctx.load(contents)
+            .set_name(format!("@{}", tasks_file.to_string_lossy()))
+            .set_environment(role_env(ctx, role, &role_dir)?)
+            .call::<MultiValue>(vars)
+    })?;
+    lua.globals().set("role", f)?;
+    Ok(())
+}
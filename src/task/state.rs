@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::TaskError;
+
+use super::{registry::TaskRegistry, Task, TaskHandle};
+
+/// Where the last run is recorded, relative to the project dir.
+const LAST_RUN: &str = ".hpg/last-run.json";
+
+/// A task as recorded for `--resume`. Tasks without a global name count
+/// too, so they're identified by the order they were defined in, with the
+/// description checked so an edited config doesn't resume the wrong task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskKey {
+    pub id: usize,
+    pub description: String,
+}
+
+impl TaskKey {
+    pub fn new(task: &Task) -> TaskKey {
+        TaskKey {
+            id: task.id.0,
+            description: task.description.clone(),
+        }
+    }
+
+    /// The task in this config, if it's still the same task.
+    fn resolve(&self, registry: &TaskRegistry) -> Option<TaskHandle> {
+        registry
+            .tasks()
+            .into_iter()
+            .find(|t| TaskKey::new(t) == *self)
+            .map(|t| t.id)
+    }
+}
+
+/// What the last (non-check) run did, so `--resume` can pick up where it
+/// left off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LastRun {
+    /// Targets that were requested, including defaults and tagged tasks.
+    pub targets: Vec<TaskKey>,
+    /// Tasks that succeeded, in this run or one it resumed.
+    pub completed: Vec<TaskKey>,
+}
+
+impl LastRun {
+    pub fn load() -> Result<LastRun, TaskError> {
+        let path = Path::new(LAST_RUN);
+        if !path.exists() {
+            return Err(TaskError::Action("No previous run to resume".into()));
+        }
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| TaskError::Action(format!("Couldn't read {}: {}", LAST_RUN, e)))
+    }
+
+    pub fn save(&self) -> Result<(), TaskError> {
+        let path = Path::new(LAST_RUN);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| TaskError::Action(format!("Couldn't write {}: {}", LAST_RUN, e)))?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// What to record for a run: the tasks it found already done that
+    /// `previous` completed, and the tasks that succeeded in it. Tasks skipped
+    /// over by `--start-at` never ran, so they aren't recorded.
+    pub fn after_run(
+        targets: Vec<TaskKey>,
+        previous: Option<&LastRun>,
+        already_done: &[Task],
+        succeeded: &[Task],
+    ) -> LastRun {
+        let resumed = already_done
+            .iter()
+            .filter(|t| previous.is_some_and(|p| p.is_completed(t)));
+        LastRun {
+            targets,
+            completed: resumed.chain(succeeded).map(TaskKey::new).collect(),
+        }
+    }
+
+    pub fn is_completed(&self, task: &Task) -> bool {
+        self.completed.contains(&TaskKey::new(task))
+    }
+
+    /// The targets of the last run, failing if the config no longer has one.
+    pub fn target_handles(&self, registry: &TaskRegistry) -> Result<Vec<TaskHandle>, TaskError> {
+        self.targets
+            .iter()
+            .map(|k| {
+                k.resolve(registry).ok_or_else(|| {
+                    TaskError::Action(format!(
+                        "Target '{}' of the last run is no longer in the config",
+                        k.description
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(id: usize, description: &str) -> Task {
+        Task::new(id, description.to_string(), Vec::new())
+    }
+
+    fn keys(tasks: &[&Task]) -> Vec<TaskKey> {
+        tasks.iter().map(|t| TaskKey::new(t)).collect()
+    }
+
+    #[test]
+    fn tasks_match_by_id_and_description() {
+        let last = LastRun {
+            targets: Vec::new(),
+            completed: keys(&[&task(1, "install"), &task(2, "configure")]),
+        };
+        assert!(last.is_completed(&task(1, "install")));
+        // Same description, defined elsewhere
+        assert!(!last.is_completed(&task(3, "install")));
+        // Edited since the last run
+        assert!(!last.is_completed(&task(2, "configure nginx")));
+    }
+
+    #[test]
+    fn records_tasks_that_succeeded() {
+        let tasks = [task(1, "a"), task(2, "b"), task(3, "c")];
+        let targets = keys(&[&tasks[2]]);
+        let last = LastRun::after_run(targets.clone(), None, &[], &tasks[..2]);
+        assert_eq!(last.targets, targets);
+        assert_eq!(last.completed, keys(&[&tasks[0], &tasks[1]]));
+    }
+
+    #[test]
+    fn keeps_tasks_completed_by_the_resumed_run() {
+        let tasks = [task(1, "a"), task(2, "b"), task(3, "c")];
+        let previous = LastRun {
+            targets: keys(&[&tasks[2]]),
+            completed: keys(&[&tasks[0]]),
+        };
+        let last = LastRun::after_run(Vec::new(), Some(&previous), &tasks[..1], &tasks[1..2]);
+        assert_eq!(last.completed, keys(&[&tasks[0], &tasks[1]]));
+    }
+
+    #[test]
+    fn doesnt_record_tasks_skipped_by_start_at() {
+        let tasks = [task(1, "a"), task(2, "b"), task(3, "c")];
+        // Started at b, so a never ran
+        let last = LastRun::after_run(Vec::new(), None, &tasks[..1], &tasks[1..2]);
+        assert_eq!(last.completed, keys(&[&tasks[1]]));
+
+        // Resuming with --start-at c, where a never ran in the resumed run either
+        let last = LastRun::after_run(Vec::new(), Some(&last), &tasks[..2], &tasks[2..]);
+        assert_eq!(last.completed, keys(&[&tasks[1], &tasks[2]]));
+    }
+
+    #[test]
+    fn resolves_targets_in_the_current_config() {
+        let registry = TaskRegistry::new();
+        registry.register_task(task(1, "a"));
+        registry.register_task(task(2, "b"));
+        let last = LastRun {
+            targets: keys(&[&task(2, "b")]),
+            completed: Vec::new(),
+        };
+        assert_eq!(
+            last.target_handles(&registry).unwrap(),
+            vec![task(2, "b").id]
+        );
+
+        let renamed = LastRun {
+            targets: keys(&[&task(2, "c")]),
+            completed: Vec::new(),
+        };
+        let err = renamed.target_handles(&registry).unwrap_err();
+        assert!(err.to_string().contains("Target 'c' of the last run"));
+    }
+}
@@ -1,40 +1,160 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
-use anyhow::{anyhow, Context};
-use mlua::{Lua, MetaMethod, UserData, Value};
+use anyhow::anyhow;
+use clap::ValueEnum;
+use mlua::{Lua, MetaMethod, Table, UserData, Value};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     actions::util,
-    error::{self},
+    error::{self, TaskError},
+    secrets,
 };
 
+use super::roles;
+
+/// How tables found in more than one variable source are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MapMerge {
+    // Merge keys recursively, so a later source only replaces the keys it sets
+    #[default]
+    Deep,
+    // A later source replaces the whole table
+    Replace,
+}
+
+/// How lists found in more than one variable source are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ListMerge {
+    // A later source replaces the whole list
+    #[default]
+    Replace,
+    // Items from a later source are added to the end
+    Append,
+}
+
+/// How variables from different sources are merged, e.g. from the
+/// `[merge]` table of an inventory.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MergeStrategy {
+    pub maps: MapMerge,
+    pub lists: ListMerge,
+}
+
+/// A variable's final value and where it came from, for `hpg vars --explain`.
+#[derive(Debug, Clone)]
+pub struct VarSource {
+    /// Dotted path of the value, e.g. `nginx.port`.
+    pub key: String,
+    pub value: serde_json::Value,
+    pub source: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Variables {
     raw: serde_json::Value,
+    /// Which source each value came from, by dotted path. Only kept locally
+    /// to explain the merged variables.
+    #[serde(skip)]
+    sources: BTreeMap<String, String>,
+    /// Values that were encrypted, to be redacted from output.
+    #[serde(default)]
+    secrets: Vec<String>,
+    /// How the sources were merged, which is also how defaults are merged
+    /// under them.
+    #[serde(default)]
+    strategy: MergeStrategy,
+    /// The role whose defaults these variables see, as numbered by
+    /// `roles::role_default()`. Only the `vars` of a role's `tasks.lua` has one.
+    #[serde(skip)]
+    role: Option<usize>,
 }
 
 impl Variables {
     pub fn from_json(json: serde_json::Value) -> Variables {
-        Variables { raw: json }
+        Variables {
+            raw: json,
+            sources: BTreeMap::new(),
+            secrets: Vec::new(),
+            strategy: MergeStrategy::default(),
+            role: None,
+        }
     }
 
-    pub fn from_toml_map(
-        map: &HashMap<String, toml::Value>,
-    ) -> Result<Variables, serde_json::Error> {
-        let json = serde_json::to_value(map)?;
-        Ok(Variables::from_json(json))
+    /// The same variables, with the defaults of the given role.
+    pub(crate) fn for_role(&self, role: usize) -> Variables {
+        Variables {
+            role: Some(role),
+            ..self.clone()
+        }
+    }
+
+    /// Like `from_json`, decrypting any encrypted values.
+    pub fn from_json_with_secrets(mut json: serde_json::Value) -> Result<Variables, anyhow::Error> {
+        let mut found = Vec::new();
+        secrets::decrypt_values(&mut json, &mut found)?;
+        secrets::register(&found);
+        Ok(Variables {
+            secrets: found,
+            ..Variables::from_json(json)
+        })
+    }
+
+    pub fn secrets(&self) -> &[String] {
+        &self.secrets
     }
 
-    pub fn from_map(map: &HashMap<String, String>) -> Result<Variables, serde_json::Error> {
+    /// Record `source` as where every value came from.
+    pub fn with_source(mut self, source: &str) -> Variables {
+        let mut values = Vec::new();
+        leaves("", &self.raw, &mut values);
+        self.sources = values
+            .into_iter()
+            .map(|(k, _)| (k, source.to_string()))
+            .collect();
+        self
+    }
+
+    pub fn from_toml_map(map: &HashMap<String, toml::Value>) -> Result<Variables, anyhow::Error> {
         let json = serde_json::to_value(map)?;
-        Ok(Variables::from_json(json))
+        Variables::from_json_with_secrets(json)
     }
 
+    /// Read a variables file, parsed as TOML or YAML by its extension, or
+    /// as JSON otherwise. Parse errors include the file and line. Files
+    /// encrypted with `hpg secret` are decrypted first, and all their values
+    /// are treated as secrets.
     pub fn from_file(f: &str) -> Result<Variables, anyhow::Error> {
-        let s = crate::load_file(f)?;
-        let json = serde_json::from_str(&s).with_context(|| format!("File: {}", f))?;
-        Ok(Variables::from_json(json))
+        let s = crate::load_file(f).map_err(|e| anyhow!("Couldn't read {}: {}", f, e))?;
+        let encrypted = secrets::is_encrypted_file(&s);
+        let s = if encrypted {
+            secrets::decrypt_file_contents(&s).map_err(|e| anyhow!("{}: {}", f, e))?
+        } else {
+            s
+        };
+        let extension = Path::new(f).extension().and_then(|e| e.to_str());
+        let json: serde_json::Value = match extension {
+            Some("toml") => toml::from_str(&s).map_err(|e| anyhow!("{}: {}", f, e))?,
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&s).map_err(|e| anyhow!("{}: {}", f, e))?
+            }
+            _ => serde_json::from_str(&s).map_err(|e| anyhow!("{}: {}", f, e))?,
+        };
+        if !json.is_object() {
+            return Err(anyhow!(
+                "{}: Variables must be a table of names to values",
+                f
+            ));
+        }
+        // Only values encrypted on their own are secrets, the rest of an
+        // encrypted file is just kept out of the repo
+        Variables::from_json_with_secrets(json).map_err(|e| anyhow!("{}: {}", f, e))
     }
 
     fn get_from_raw(&self, key: &str) -> Result<Option<&serde_json::Value>, mlua::Error> {
@@ -52,60 +172,338 @@ impl Variables {
         ctx: &'lua Lua,
         key: &str,
     ) -> Result<Option<mlua::Value>, mlua::Error> {
-        let val: Option<mlua::Value> = ctx.named_registry_value(key)?;
-        Ok(val)
+        let defaults: Option<Table> = ctx.named_registry_value("var_defaults")?;
+        match defaults {
+            Some(d) => d.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// The value of `key`, from the variables given, then the defaults of the
+    /// role these variables belong to, if any, then defaults set in the
+    /// config. Tables found in more than one of those are merged like the
+    /// variable sources are.
+    pub fn lookup(&self, ctx: &Lua, key: &str) -> Result<Option<mlua::Value>, mlua::Error> {
+        let given = self.get_from_raw(key)?;
+        let role_default = match self.role {
+            Some(role) => roles::role_default(ctx, role, key)?,
+            None => None,
+        };
+        // Lowest precedence first
+        let mut defaults: Vec<Value> = [self.get_from_registry(ctx, key)?, role_default]
+            .into_iter()
+            .flatten()
+            .collect();
+        let is_table = match given {
+            Some(v) => v.is_object(),
+            None => matches!(defaults.last(), Some(Value::Table(_))),
+        };
+        let found = defaults.len() + usize::from(given.is_some());
+        if !is_table || found < 2 || self.strategy.maps == MapMerge::Replace {
+            return match given {
+                Some(v) => Ok(Some(util::json_to_lua_value(ctx, v)?)),
+                None => Ok(defaults.pop()),
+            };
+        }
+        let mut layers = Vec::new();
+        for value in defaults {
+            layers.push(lua_to_json(value).map_err(error::task_error)?);
+        }
+        layers.extend(given.cloned());
+        let merged = layers.into_iter().reduce(|l, r| {
+            merge_values(
+                l,
+                r,
+                key,
+                self.strategy,
+                &mut BTreeMap::new(),
+                &BTreeMap::new(),
+            )
+        });
+        merged.map(|v| util::json_to_lua_value(ctx, &v)).transpose()
     }
 
     pub fn get(&self, ctx: &Lua, key: &str) -> Result<mlua::Value, mlua::Error> {
-        let val = if let Some(v) = self.get_from_raw(key)? {
-            util::json_to_lua_value(ctx, v)?
-        } else if let Some(v) = self.get_from_registry(ctx, key)? {
-            v
-        } else {
-            return Err(error::action_error(format!(
-                "Variable '{}' not defined.",
-                key
-            )));
+        self.lookup(ctx, key)?
+            .ok_or_else(|| error::action_error(format!("Variable '{}' not defined.", key)))
+    }
+
+    /// Names of the variables given, not counting defaults.
+    pub fn names(&self) -> Vec<&str> {
+        match &self.raw {
+            serde_json::Value::Object(o) => o.keys().map(|k| k.as_str()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Set a default in the config, or for the role these variables belong to.
+    pub fn set_default(&self, ctx: &Lua, key: &str, val: mlua::Value) -> Result<(), mlua::Error> {
+        if let Some(role) = self.role {
+            return roles::set_role_default(ctx, role, key, val);
+        }
+        let defaults = match ctx.named_registry_value::<Option<Table>>("var_defaults")? {
+            Some(d) => d,
+            None => {
+                let d = ctx.create_table()?;
+                ctx.set_named_registry_value("var_defaults", &d)?;
+                d
+            }
         };
-        Ok(val)
+        defaults.set(key, val)
     }
 
-    pub fn set_default(
-        &mut self,
+    /// Merge `other` on top of these variables.
+    pub fn merge(
+        mut self,
+        other: Variables,
+        strategy: MergeStrategy,
+    ) -> Result<Variables, anyhow::Error> {
+        use serde_json::Value;
+
+        // The top level is always merged by key, whatever the strategy
+        let (Value::Object(mut left), Value::Object(right)) = (self.raw, other.raw) else {
+            return Err(anyhow!("Only JSON Objects can be merged"));
+        };
+        for (k, v) in right {
+            let value = match left.remove(&k) {
+                Some(l) => merge_values(l, v, &k, strategy, &mut self.sources, &other.sources),
+                None => {
+                    copy_sources(&k, &mut self.sources, &other.sources);
+                    v
+                }
+            };
+            left.insert(k, value);
+        }
+        for s in other.secrets {
+            if !self.secrets.contains(&s) {
+                self.secrets.push(s);
+            }
+        }
+        Ok(Variables {
+            raw: Value::Object(left),
+            sources: self.sources,
+            secrets: self.secrets,
+            strategy,
+            role: self.role,
+        })
+    }
+
+    /// Defaults set in the config, then the defaults of the role these
+    /// variables belong to, then these variables, merged the same way the
+    /// variable sources were.
+    fn layered(&self, ctx: &Lua) -> Result<Variables, TaskError> {
+        let mut layers = Vec::new();
+        let lua_defaults: Vec<(String, Value)> =
+            match ctx.named_registry_value::<Option<Table>>("var_defaults")? {
+                Some(d) => d.pairs().collect::<mlua::Result<_>>()?,
+                None => Vec::new(),
+            };
+        let mut defaults = vec![(lua_defaults, "Lua default".to_string())];
+        if let Some(role) = self.role {
+            defaults.push((roles::role_defaults(ctx, role)?, role_source(ctx, role)?));
+        }
+        for (values, source) in defaults {
+            let mut map = serde_json::Map::new();
+            for (key, value) in values {
+                map.insert(key, lua_to_json(value)?);
+            }
+            layers.push(Variables::from_json(serde_json::Value::Object(map)).with_source(&source));
+        }
+        layers.push(self.clone());
+        let mut layered = Variables::default();
+        for layer in layers {
+            layered = layered
+                .merge(layer, self.strategy)
+                .map_err(|e| TaskError::Action(e.to_string()))?;
+        }
+        Ok(layered)
+    }
+
+    /// The value every variable ends up with, or just the one at `key`,
+    /// which may be a dotted path into a table.
+    pub fn resolve(
+        &self,
         ctx: &Lua,
-        key: &str,
-        val: mlua::Value,
-    ) -> Result<(), mlua::Error> {
-        ctx.set_named_registry_value(key, val)?;
-        Ok(())
+        key: Option<&str>,
+    ) -> Result<Option<serde_json::Value>, TaskError> {
+        let resolved = self.layered(ctx)?.raw;
+        Ok(match key {
+            Some(key) => {
+                let pointer = format!("/{}", key.replace('.', "/"));
+                resolved.pointer(&pointer).cloned()
+            }
+            None => Some(resolved),
+        })
     }
 
-    pub fn merge(self, other: Variables) -> Result<Variables, anyhow::Error> {
-        let raw = merge_objects(self.raw, other.raw)?;
-        Ok(Variables::from_json(raw))
+    /// Every value at or under `key` (or every value), with the source it
+    /// came from. Tables are flattened into dotted paths. Outside of a role,
+    /// the defaults of every role are listed too, though each only applies to
+    /// its own role.
+    pub fn explain(&self, ctx: &Lua, key: Option<&str>) -> Result<Vec<VarSource>, TaskError> {
+        let mut explained = self.layered(ctx)?.sourced_leaves();
+        if self.role.is_none() {
+            for (role, _) in roles::loaded_roles(ctx)? {
+                let source = role_source(ctx, role)?;
+                let scoped = self.for_role(role).layered(ctx)?.sourced_leaves();
+                explained.extend(scoped.into_iter().filter(|v| v.source == source));
+            }
+        }
+        if let Some(key) = key {
+            explained.retain(|v| is_under(&v.key, key));
+        }
+        explained.sort_by(|a, b| (&a.key, &a.source).cmp(&(&b.key, &b.source)));
+        // A role loaded twice with the same defaults
+        explained.dedup_by(|a, b| a.key == b.key && a.source == b.source && a.value == b.value);
+        Ok(explained)
+    }
+
+    /// Every value with the source it came from, by dotted path.
+    fn sourced_leaves(&self) -> Vec<VarSource> {
+        let mut values = Vec::new();
+        leaves("", &self.raw, &mut values);
+        values
+            .into_iter()
+            .map(|(key, value)| VarSource {
+                source: self
+                    .sources
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| "unknown".to_string()),
+                key,
+                value: value.clone(),
+            })
+            .collect()
     }
 }
 
+/// Where the defaults of a role come from, e.g. `role nginx default`.
+fn role_source(ctx: &Lua, role: usize) -> Result<String, mlua::Error> {
+    Ok(format!("role {} default", roles::role_name(ctx, role)?))
+}
+
 impl Default for Variables {
     fn default() -> Self {
-        Self {
-            raw: serde_json::Value::Object(serde_json::Map::new()),
+        Variables::from_json(serde_json::Value::Object(serde_json::Map::new()))
+    }
+}
+
+/// A Lua value as JSON. Unlike `lua_value_to_json`, lists stay lists and
+/// whole numbers become integers, as they were most likely given.
+pub(crate) fn lua_to_json(value: Value) -> Result<serde_json::Value, TaskError> {
+    Ok(match value {
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => (n as i64).into(),
+        Value::Table(t) if t.raw_len() > 0 && t.raw_len() == t.pairs::<Value, Value>().count() => {
+            let items: Result<Vec<_>, TaskError> = t
+                .sequence_values::<Value>()
+                .map(|v| lua_to_json(v?))
+                .collect();
+            serde_json::Value::Array(items?)
+        }
+        Value::Table(t) => {
+            let mut map = serde_json::Map::new();
+            for pair in t.pairs::<Value, Value>() {
+                let (k, v) = pair?;
+                let k = match k {
+                    Value::String(s) => s.to_string_lossy(),
+                    k => k.to_string()?,
+                };
+                map.insert(k, lua_to_json(v)?);
+            }
+            serde_json::Value::Object(map)
         }
+        v => util::lua_value_to_json(v)?,
+    })
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
     }
 }
 
-fn merge_objects(
+fn is_under(key: &str, path: &str) -> bool {
+    key == path || key.strip_prefix(path).is_some_and(|r| r.starts_with('.'))
+}
+
+/// Collect the values that aren't tables with keys, by dotted path.
+fn leaves<'a>(
+    path: &str,
+    value: &'a serde_json::Value,
+    out: &mut Vec<(String, &'a serde_json::Value)>,
+) {
+    match value {
+        // Empty tables are values of their own, except for the variables themselves
+        serde_json::Value::Object(o) if !o.is_empty() || path.is_empty() => {
+            for (k, v) in o {
+                leaves(&join_path(path, k), v, out);
+            }
+        }
+        _ => out.push((path.to_string(), value)),
+    }
+}
+
+/// Replace the sources of everything under `path` with those from `right`.
+fn copy_sources(
+    path: &str,
+    sources: &mut BTreeMap<String, String>,
+    right: &BTreeMap<String, String>,
+) {
+    sources.retain(|k, _| !is_under(k, path));
+    sources.extend(
+        right
+            .iter()
+            .filter(|(k, _)| is_under(k, path))
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+}
+
+/// Merge the value at `path` from a later source on top of an earlier one,
+/// keeping track of where each value came from.
+fn merge_values(
     left: serde_json::Value,
     right: serde_json::Value,
-) -> Result<serde_json::Value, anyhow::Error> {
+    path: &str,
+    strategy: MergeStrategy,
+    sources: &mut BTreeMap<String, String>,
+    right_sources: &BTreeMap<String, String>,
+) -> serde_json::Value {
     use serde_json::Value;
 
     match (left, right) {
-        (Value::Object(mut left), Value::Object(mut right)) => {
-            left.append(&mut right);
-            Ok(Value::Object(left))
+        (Value::Object(mut left), Value::Object(right)) if strategy.maps == MapMerge::Deep => {
+            // An empty table was a value of its own
+            sources.remove(path);
+            for (k, v) in right {
+                let child = join_path(path, &k);
+                let value = match left.remove(&k) {
+                    Some(l) => merge_values(l, v, &child, strategy, sources, right_sources),
+                    None => {
+                        copy_sources(&child, sources, right_sources);
+                        v
+                    }
+                };
+                left.insert(k, value);
+            }
+            Value::Object(left)
+        }
+        (Value::Array(mut left), Value::Array(right)) if strategy.lists == ListMerge::Append => {
+            left.extend(right);
+            if let Some(r) = right_sources.get(path) {
+                let source = match sources.get(path) {
+                    Some(l) if l != r => format!("{}, {}", l, r),
+                    _ => r.clone(),
+                };
+                sources.insert(path.to_string(), source);
+            }
+            Value::Array(left)
+        }
+        (_, right) => {
+            copy_sources(path, sources, right_sources);
+            right
         }
-        _ => Err(anyhow!("Only JSON Objects can be merged")),
     }
 }
 
@@ -115,7 +513,7 @@ impl UserData for Variables {
             let v = this.get(ctx, &idx)?;
             Ok(v)
         });
-        methods.add_meta_method_mut(
+        methods.add_meta_method(
             MetaMethod::NewIndex,
             |ctx, this, (idx, val): (String, Value)| {
                 this.set_default(ctx, &idx, val)?;
@@ -124,3 +522,302 @@ impl UserData for Variables {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn vars(json: serde_json::Value, source: &str) -> Variables {
+        Variables::from_json(json).with_source(source)
+    }
+
+    fn strategy(maps: MapMerge, lists: ListMerge) -> MergeStrategy {
+        MergeStrategy { maps, lists }
+    }
+
+    fn sources(vars: &Variables) -> Vec<(&str, &str)> {
+        vars.sources
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    /// A Lua state with roles loaded with the given defaults, numbered from
+    /// 1, and defaults set in the config.
+    fn lua_with_roles(roles: &[(&str, serde_json::Value)], config: serde_json::Value) -> Lua {
+        let lua = Lua::new();
+        let loaded = lua.create_table().unwrap();
+        for (name, defaults) in roles {
+            let role = lua.create_table().unwrap();
+            role.set("name", *name).unwrap();
+            role.set("defaults", util::json_to_lua_value(&lua, defaults).unwrap())
+                .unwrap();
+            loaded.raw_push(role).unwrap();
+        }
+        lua.set_named_registry_value("roles", loaded).unwrap();
+        lua.set_named_registry_value(
+            "var_defaults",
+            util::json_to_lua_value(&lua, &config).unwrap(),
+        )
+        .unwrap();
+        lua
+    }
+
+    fn lua_with_defaults(role: serde_json::Value, config: serde_json::Value) -> Lua {
+        lua_with_roles(&[("nginx", role)], config)
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let merged = vars(json!({"a": 1, "b": 1}), "file")
+            .merge(
+                vars(json!({"b": 2, "c": 2}), "-v"),
+                MergeStrategy::default(),
+            )
+            .unwrap();
+        assert_eq!(merged.raw, json!({"a": 1, "b": 2, "c": 2}));
+        assert_eq!(
+            sources(&merged),
+            vec![("a", "file"), ("b", "-v"), ("c", "-v")]
+        );
+    }
+
+    #[test]
+    fn deep_merges_tables() {
+        let merged = vars(json!({"nginx": {"user": "www", "port": 80}}), "file")
+            .merge(
+                vars(json!({"nginx": {"port": 8080}}), "-v"),
+                MergeStrategy::default(),
+            )
+            .unwrap();
+        assert_eq!(merged.raw, json!({"nginx": {"user": "www", "port": 8080}}));
+        assert_eq!(
+            sources(&merged),
+            vec![("nginx.port", "-v"), ("nginx.user", "file")]
+        );
+    }
+
+    #[test]
+    fn replaces_tables() {
+        let merged = vars(json!({"nginx": {"user": "www", "port": 80}}), "file")
+            .merge(
+                vars(json!({"nginx": {"port": 8080}}), "-v"),
+                strategy(MapMerge::Replace, ListMerge::Replace),
+            )
+            .unwrap();
+        assert_eq!(merged.raw, json!({"nginx": {"port": 8080}}));
+        assert_eq!(sources(&merged), vec![("nginx.port", "-v")]);
+    }
+
+    #[test]
+    fn replaces_or_appends_lists() {
+        let left = vars(json!({"pkgs": ["a"], "opts": {"flags": ["x"]}}), "file");
+        let right = vars(json!({"pkgs": ["b"], "opts": {"flags": ["y"]}}), "-v");
+
+        let replaced = left
+            .clone()
+            .merge(right.clone(), MergeStrategy::default())
+            .unwrap();
+        assert_eq!(
+            replaced.raw,
+            json!({"pkgs": ["b"], "opts": {"flags": ["y"]}})
+        );
+
+        let appended = left
+            .merge(right, strategy(MapMerge::Deep, ListMerge::Append))
+            .unwrap();
+        assert_eq!(
+            appended.raw,
+            json!({"pkgs": ["a", "b"], "opts": {"flags": ["x", "y"]}})
+        );
+        assert_eq!(
+            sources(&appended),
+            vec![("opts.flags", "file, -v"), ("pkgs", "file, -v")]
+        );
+    }
+
+    #[test]
+    fn a_value_replaces_a_table() {
+        let merged = vars(json!({"nginx": {"port": 80}}), "file")
+            .merge(
+                vars(json!({"nginx": "off"}), "-v"),
+                MergeStrategy::default(),
+            )
+            .unwrap();
+        assert_eq!(merged.raw, json!({"nginx": "off"}));
+        assert_eq!(sources(&merged), vec![("nginx", "-v")]);
+    }
+
+    #[test]
+    fn defaults_are_merged_under_given_variables() {
+        let lua = lua_with_defaults(
+            json!({"nginx": {"user": "www-data", "port": 80}}),
+            json!({"nginx": {"workers": 4, "user": "nobody"}, "debug": false}),
+        );
+        let given = Variables::default()
+            .merge(
+                vars(json!({"nginx": {"port": 8080}}), "-v"),
+                MergeStrategy::default(),
+            )
+            .unwrap();
+        let given = given.for_role(1);
+        assert_eq!(
+            given.resolve(&lua, None).unwrap(),
+            Some(json!({
+                "nginx": {"user": "www-data", "port": 8080, "workers": 4},
+                "debug": false,
+            }))
+        );
+        assert_eq!(
+            given.resolve(&lua, Some("nginx.user")).unwrap(),
+            Some(json!("www-data"))
+        );
+
+        let nginx: Table = given
+            .get(&lua, "nginx")
+            .unwrap()
+            .as_table()
+            .unwrap()
+            .clone();
+        assert_eq!(nginx.get::<String>("user").unwrap(), "www-data");
+        assert_eq!(nginx.get::<i64>("port").unwrap(), 8080);
+        assert_eq!(nginx.get::<i64>("workers").unwrap(), 4);
+    }
+
+    #[test]
+    fn explains_where_merged_defaults_came_from() {
+        let lua = lua_with_defaults(
+            json!({"nginx": {"user": "www-data", "port": 80}}),
+            json!({"nginx": {"workers": 4}}),
+        );
+        let given = vars(json!({"nginx": {"port": 8080}}), "-v");
+        let explained: Vec<(String, serde_json::Value, String)> = given
+            .explain(&lua, Some("nginx"))
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.key, v.value, v.source))
+            .collect();
+        assert_eq!(
+            explained,
+            vec![
+                ("nginx.port".into(), json!(8080), "-v".into()),
+                (
+                    "nginx.user".into(),
+                    json!("www-data"),
+                    "role nginx default".into()
+                ),
+                ("nginx.workers".into(), json!(4), "Lua default".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_tables_hide_defaults() {
+        let lua = lua_with_defaults(json!({"nginx": {"user": "www-data"}}), json!({}));
+        let given = Variables::default()
+            .merge(
+                vars(json!({"nginx": {"port": 8080}}), "-v"),
+                strategy(MapMerge::Replace, ListMerge::Replace),
+            )
+            .unwrap()
+            .for_role(1);
+        assert_eq!(
+            given.resolve(&lua, Some("nginx")).unwrap(),
+            Some(json!({"port": 8080}))
+        );
+        let nginx: Table = given
+            .get(&lua, "nginx")
+            .unwrap()
+            .as_table()
+            .unwrap()
+            .clone();
+        assert_eq!(nginx.get::<Option<String>>("user").unwrap(), None);
+    }
+
+    #[test]
+    fn role_defaults_only_apply_to_their_role() {
+        let lua = lua_with_roles(
+            &[
+                ("nginx", json!({"port": 80})),
+                ("app", json!({"port": 8080})),
+            ],
+            json!({}),
+        );
+        let given = Variables::default();
+        let port = |vars: &Variables| vars.lookup(&lua, "port").unwrap().and_then(|v| v.as_f64());
+        assert_eq!(port(&given.for_role(1)), Some(80.0));
+        assert_eq!(port(&given.for_role(2)), Some(8080.0));
+        assert_eq!(port(&given), None);
+
+        given
+            .for_role(1)
+            .set_default(&lua, "workers", Value::Integer(4))
+            .unwrap();
+        assert!(given.for_role(2).lookup(&lua, "workers").unwrap().is_none());
+
+        let explained: Vec<(String, serde_json::Value, String)> = given
+            .explain(&lua, None)
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.key, v.value, v.source))
+            .collect();
+        assert_eq!(
+            explained,
+            vec![
+                ("port".into(), json!(8080), "role app default".into()),
+                ("port".into(), json!(80), "role nginx default".into()),
+                ("workers".into(), json!(4), "role nginx default".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        let lua = lua_with_defaults(json!({}), json!({}));
+        assert!(Variables::default().get(&lua, "missing").is_err());
+    }
+
+    /// Write `contents` to a file named `name` in a fresh temporary dir.
+    fn vars_file(name: &str, contents: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("hpg-vars-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn reads_files_by_extension() {
+        let expected = json!({"name": "web", "nginx": {"port": 80, "modules": ["a", "b"]}});
+        for (name, contents) in [
+            (
+                "vars.toml",
+                "name = \"web\"\n[nginx]\nport = 80\nmodules = [\"a\", \"b\"]\n",
+            ),
+            (
+                "vars.yaml",
+                "name: web\nnginx:\n  port: 80\n  modules: [a, b]\n",
+            ),
+            (
+                "vars.json",
+                r#"{"name": "web", "nginx": {"port": 80, "modules": ["a", "b"]}}"#,
+            ),
+        ] {
+            let vars = Variables::from_file(&vars_file(name, contents)).unwrap();
+            assert_eq!(vars.raw, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn file_errors_name_the_file() {
+        let path = vars_file("bad.toml", "name = \n");
+        let err = Variables::from_file(&path).unwrap_err().to_string();
+        assert!(err.starts_with(&path), "{}", err);
+
+        let path = vars_file("list.yml", "- a\n- b\n");
+        let err = Variables::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("Variables must be a table"), "{}", err);
+    }
+}
@@ -7,15 +7,25 @@ use std::{
     time::{Duration, Instant},
 };
 
-use console::{style, Term};
+use console::{pad_str, style, Alignment, StyledObject, Term};
 use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 
+use super::{StepDecision, StepPrompt, TaskRecap, TrackedTask};
+
+fn in_flight(tasks: &[TrackedTask]) -> String {
+    tasks
+        .iter()
+        .map(|t| t.description.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[derive(Debug)]
 pub struct PrettyTracker {
     console: Term,
     bars: MultiProgress,
     run_bar: Mutex<Option<ProgressBar>>,
-    current_task: Mutex<Option<String>>,
+    current_tasks: Mutex<Vec<TrackedTask>>,
     started: Mutex<Option<Instant>>,
     debug: AtomicBool,
 }
@@ -28,7 +38,7 @@ impl PrettyTracker {
             console: Term::stdout(),
             bars,
             run_bar: Mutex::new(None),
-            current_task: Mutex::new(None),
+            current_tasks: Mutex::new(Vec::new()),
             started: Mutex::new(None),
             debug: AtomicBool::new(false),
         }
@@ -68,10 +78,47 @@ impl PrettyTracker {
             .suspend(|| self.console.write_line(&output).unwrap());
     }
 
-    pub fn task(&self, msg: String) {
-        *self.current_task.lock().unwrap() = Some(msg.clone());
+    pub fn file_diff(&self, diff: &str) {
+        let mut lines = Vec::new();
+        for line in diff.lines() {
+            let styled = if line.starts_with("+++") || line.starts_with("---") {
+                style(line).bold()
+            } else if line.starts_with('+') {
+                style(line).green()
+            } else if line.starts_with('-') {
+                style(line).red()
+            } else if line.starts_with("@@") {
+                style(line).cyan()
+            } else {
+                style(line)
+            };
+            lines.push(format!("    {}", styled));
+        }
+        let output = lines.join("\n");
+        self.bars
+            .suspend(|| self.console.write_line(&output).unwrap());
+    }
+
+    pub fn task(&self, task: TrackedTask) {
+        let mut tasks = self.current_tasks.lock().unwrap();
+        tasks.push(task);
         if let Some(rb) = &*self.run_bar.lock().unwrap() {
-            rb.set_message(msg);
+            rb.set_message(in_flight(&tasks));
+        }
+    }
+
+    /// Remove a finished task from the in-flight list and print its outcome.
+    fn task_finished(&self, task: &TrackedTask, outcome: String) {
+        let mut tasks = self.current_tasks.lock().unwrap();
+        if let Some(idx) = tasks.iter().position(|t| t.id == task.id) {
+            tasks.remove(idx);
+            let _ = self
+                .bars
+                .println(format!("{} {}", outcome, task.description));
+        }
+        if let Some(rb) = &*self.run_bar.lock().unwrap() {
+            rb.set_message(in_flight(&tasks));
+            rb.inc(1);
         }
     }
 
@@ -105,8 +152,13 @@ impl PrettyTracker {
         *opt = None;
     }
 
+    /// Start a batch of `count` tasks, or add them to the batch in progress.
     pub fn run(&self, count: usize) {
         let mut rb = self.run_bar.lock().unwrap();
+        if let Some(bar) = &*rb {
+            bar.inc_length(count as u64);
+            return;
+        }
         if rb.is_none() {
             let bar = ProgressBar::new(count as u64).with_style(
                 ProgressStyle::with_template("[{pos}/{len}] ({elapsed}) {spinner} {msg}").unwrap(),
@@ -119,46 +171,59 @@ impl PrettyTracker {
         *self.started.lock().unwrap() = Some(Instant::now());
     }
 
-    pub fn task_success(&self) {
-        let mut task = self.current_task.lock().unwrap();
-        if let Some(t) = &*task {
-            let _ = self
-                .bars
-                .println(format!("{} {}", style("✓ SUCCESS").green(), t));
-        }
-        *task = None;
-        if let Some(rb) = &*self.run_bar.lock().unwrap() {
-            rb.inc(1);
-        }
+    pub fn task_success(&self, task: &TrackedTask) {
+        self.task_finished(task, style("✓ SUCCESS").green().to_string());
     }
 
-    pub fn task_skip(&self) {
-        let mut task = self.current_task.lock().unwrap();
-        if let Some(t) = &*task {
-            let _ = self
-                .bars
-                .println(format!("{} {}", style("⧖ SKIPPED").cyan(), t));
-        }
-        *task = None;
-        if let Some(rb) = &*self.run_bar.lock().unwrap() {
-            rb.inc(1);
-        }
+    pub fn task_skip(&self, task: &TrackedTask) {
+        self.task_finished(task, style("⧖ SKIPPED").cyan().to_string());
     }
 
-    pub fn task_fail(&self) {
-        let mut task = self.current_task.lock().unwrap();
-        if let Some(t) = &*task {
-            let _ = self
-                .bars
-                .println(format!("{} {}", style("✗ FAILED").red(), t));
+    pub fn task_fail(&self, task: &TrackedTask) {
+        self.task_finished(task, style("✗ FAILED").red().to_string());
+    }
+
+    fn print_recap(&self, recap: &[TaskRecap]) {
+        if recap.is_empty() {
+            return;
         }
-        *task = None;
-        if let Some(rb) = &*self.run_bar.lock().unwrap() {
-            rb.inc(1);
+        let width = recap
+            .iter()
+            .map(|r| console::measure_text_width(&r.task))
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        let cell =
+            |n: usize, width: usize, color: fn(StyledObject<String>) -> StyledObject<String>| {
+                let s = style(format!("{:>width$}", n));
+                if n > 0 {
+                    color(s).to_string()
+                } else {
+                    s.dim().to_string()
+                }
+            };
+        println!("{}", style("Recap").yellow());
+        println!(
+            "  {}  {:>4}  {:>7}  {:>7}  {:>6}",
+            pad_str("TASK", width, Alignment::Left, None),
+            "OK",
+            "CHANGED",
+            "SKIPPED",
+            "FAILED"
+        );
+        for r in recap {
+            println!(
+                "  {}  {}  {}  {}  {}",
+                pad_str(&r.task, width, Alignment::Left, None),
+                cell(r.ok, 4, StyledObject::green),
+                cell(r.changed, 7, StyledObject::yellow),
+                cell(r.skipped, 7, StyledObject::cyan),
+                cell(r.failed, 6, StyledObject::red),
+            );
         }
     }
 
-    pub fn finish_success(&self) {
+    pub fn finish_success(&self, recap: &[TaskRecap]) {
         let msg = if let Some(started) = &*self.started.lock().unwrap() {
             format!(
                 "{} Done in {}.",
@@ -170,11 +235,12 @@ impl PrettyTracker {
         };
         if let Some(rb) = &*self.run_bar.lock().unwrap() {
             rb.finish_and_clear();
+            self.print_recap(recap);
             println!("{}", msg);
         }
     }
 
-    pub fn finish_fail(&self) {
+    pub fn finish_fail(&self, recap: &[TaskRecap]) {
         let msg = if let Some(started) = &*self.started.lock().unwrap() {
             format!(
                 "{} One or more tasks failed or were skipped. Done in {}.",
@@ -189,10 +255,40 @@ impl PrettyTracker {
         };
         if let Some(rb) = &*self.run_bar.lock().unwrap() {
             rb.finish_and_clear();
+            self.print_recap(recap);
             println!("{}", msg);
         }
     }
 
+    /// Ask on the terminal whether to run a task. Aborts if stdin is closed.
+    pub fn prompt_step(&self, prompt: &StepPrompt) -> StepDecision {
+        self.suspend();
+        let _ =
+            self.console
+                .write_line(&format!("{} {}", style("Step:").cyan().bold(), prompt.task));
+        if !prompt.deps.is_empty() {
+            let _ = self
+                .console
+                .write_line(&format!("  depends on: {}", prompt.deps.join(", ")));
+        }
+        let decision = loop {
+            let _ = self.console.write_str("  (c)ontinue, (s)kip or (a)bort? ");
+            let _ = self.console.flush();
+            let mut answer = String::new();
+            if let Ok(0) | Err(_) = std::io::stdin().read_line(&mut answer) {
+                break StepDecision::Abort;
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "c" | "continue" => break StepDecision::Continue,
+                "s" | "skip" => break StepDecision::Skip,
+                "a" | "abort" => break StepDecision::Abort,
+                _ => continue,
+            }
+        };
+        self.resume();
+        decision
+    }
+
     pub fn suspend(&self) {
         self.bars.clear().unwrap();
         self.bars.set_draw_target(ProgressDrawTarget::hidden());
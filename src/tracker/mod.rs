@@ -1,4 +1,5 @@
 use std::{
+    cell::Cell,
     fmt::{Arguments, Debug},
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -9,7 +10,7 @@ use std::{
 };
 
 use crossbeam::channel::{self, unbounded};
-use futures_util::SinkExt;
+use futures_util::{SinkExt, StreamExt};
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
@@ -20,6 +21,7 @@ use crate::remote::{
     codec::HpgCodec,
     messages::{ExecServerMessage, HpgMessage},
 };
+use crate::secrets;
 
 use self::local::PrettyTracker;
 pub mod local;
@@ -29,15 +31,16 @@ pub trait Tracker {
     fn println(&self, args: Arguments);
     fn indent_println(&self, indent: usize, args: Arguments);
     fn run(&self, count: usize);
-    fn task(&self, task: String);
+    fn task(&self, task: TrackedTask);
     fn progressbar(&self, count: usize);
     fn progressbar_progress(&self, msg: String);
     fn progressbar_finish(&self, msg: String);
-    fn task_success(&self);
-    fn task_skip(&self);
-    fn task_fail(&self);
-    fn finish_success(&self);
-    fn finish_fail(&self);
+    fn task_success(&self, task: TrackedTask);
+    fn task_skip(&self, task: TrackedTask);
+    fn task_fail(&self, task: TrackedTask);
+    fn file_diff(&self, diff: String);
+    fn finish_success(&self, recap: Vec<TaskRecap>);
+    fn finish_fail(&self, recap: Vec<TaskRecap>);
     fn suspend_bars(&self);
     fn resume_bars(&self);
 }
@@ -67,6 +70,39 @@ pub fn init(debug: bool) -> Result<SinkHandle, std::io::Error> {
     Ok(SinkHandle { handle })
 }
 
+/// Start a tracker that drops every event, for tests of code that reports
+/// progress, once for all of them.
+#[cfg(test)]
+pub fn init_for_tests() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let (tx, rx) = unbounded();
+        EVENT_SOURCE
+            .set(EventSource::new(tx))
+            .expect("Couldn't set event source");
+        std::thread::spawn(move || rx.iter().for_each(drop));
+    });
+}
+
+thread_local! {
+    /// Whether messages printed from this thread are dropped.
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` without printing any messages it prints from this thread, e.g.
+/// while a parallel worker evaluates a config that has already been
+/// evaluated once. Task events are still sent.
+pub fn quietly<T>(f: impl FnOnce() -> T) -> T {
+    QUIET.with(|q| q.set(true));
+    let res = f();
+    QUIET.with(|q| q.set(false));
+    res
+}
+
+fn quiet() -> bool {
+    QUIET.with(|q| q.get())
+}
+
 pub fn tracker() -> &'static EventSource {
     EVENT_SOURCE.get().expect("Global tracker not initialized")
 }
@@ -75,17 +111,79 @@ pub fn sink() -> &'static EventSink {
     EVENT_SINK.get().expect("Global tracker not initialized")
 }
 
+/// A task or handler in progress. Descriptions needn't be unique, so tasks
+/// running side by side are told apart by `id`.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct TrackedTask {
+    pub id: String,
+    pub description: String,
+}
+
+impl TrackedTask {
+    fn redacted(self) -> TrackedTask {
+        TrackedTask {
+            description: secrets::redact(self.description),
+            ..self
+        }
+    }
+}
+
+/// One row of the recap table printed at the end of a run.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct TaskRecap {
+    pub task: String,
+    /// Actions that found the system already in the desired state.
+    pub ok: usize,
+    /// Actions that changed (or in check mode, would change) the system.
+    pub changed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// What `--step` asks about before running a task.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct StepPrompt {
+    pub task: String,
+    /// Direct dependencies of the task.
+    pub deps: Vec<String>,
+}
+
+/// The answer to a `--step` prompt.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum StepDecision {
+    Continue,
+    Skip,
+    Abort,
+}
+
+/// Ask whether to run a task, with `--step`. Output sent so far is shown
+/// first. Over SSH, the question is answered on the client.
+pub fn prompt_step(prompt: &StepPrompt) -> StepDecision {
+    let prompt = &StepPrompt {
+        task: secrets::redact(prompt.task.clone()),
+        deps: prompt.deps.iter().cloned().map(secrets::redact).collect(),
+    };
+    let sink = sink();
+    sink.wait_for_drain();
+    let out = &*sink.output.read().unwrap();
+    match out {
+        SinkType::Local(l) => l.prompt_step(prompt),
+        SinkType::Remote(r) => r.prompt_step(prompt),
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub enum TrackerEvent {
     Println { msg: String, indent: Option<usize> },
     Debug(String),
     BatchStart(usize),
-    BatchSuccess,
-    BatchFail,
-    TaskStart(String),
-    TaskComplete,
-    TaskFail,
-    TaskSkip,
+    BatchSuccess(Vec<TaskRecap>),
+    BatchFail(Vec<TaskRecap>),
+    TaskStart(TrackedTask),
+    TaskComplete(TrackedTask),
+    TaskFail(TrackedTask),
+    TaskSkip(TrackedTask),
+    FileDiff(String),
     ProgressStart(usize),
     ProgressInc(String),
     ProgressFinish(String),
@@ -111,19 +209,30 @@ impl EventSource {
 
 impl Tracker for EventSource {
     fn debug_println(&self, args: Arguments) {
-        let _ = self.tx.send(TrackerEvent::Debug(args.to_string()));
+        if quiet() {
+            return;
+        }
+        let _ = self
+            .tx
+            .send(TrackerEvent::Debug(secrets::redact(args.to_string())));
     }
 
     fn println(&self, args: Arguments) {
+        if quiet() {
+            return;
+        }
         let _ = self.tx.send(TrackerEvent::Println {
-            msg: args.to_string(),
+            msg: secrets::redact(args.to_string()),
             indent: None,
         });
     }
 
     fn indent_println(&self, indent: usize, args: Arguments) {
+        if quiet() {
+            return;
+        }
         let _ = self.tx.send(TrackerEvent::Println {
-            msg: args.to_string(),
+            msg: secrets::redact(args.to_string()),
             indent: Some(indent),
         });
     }
@@ -132,8 +241,8 @@ impl Tracker for EventSource {
         let _ = self.tx.send(TrackerEvent::BatchStart(count));
     }
 
-    fn task(&self, task: String) {
-        let _ = self.tx.send(TrackerEvent::TaskStart(task));
+    fn task(&self, task: TrackedTask) {
+        let _ = self.tx.send(TrackerEvent::TaskStart(task.redacted()));
     }
 
     fn progressbar(&self, count: usize) {
@@ -141,31 +250,41 @@ impl Tracker for EventSource {
     }
 
     fn progressbar_progress(&self, msg: String) {
-        let _ = self.tx.send(TrackerEvent::ProgressInc(msg));
+        let _ = self
+            .tx
+            .send(TrackerEvent::ProgressInc(secrets::redact(msg)));
     }
 
     fn progressbar_finish(&self, msg: String) {
-        let _ = self.tx.send(TrackerEvent::ProgressFinish(msg));
+        let _ = self
+            .tx
+            .send(TrackerEvent::ProgressFinish(secrets::redact(msg)));
+    }
+
+    fn task_success(&self, task: TrackedTask) {
+        let _ = self.tx.send(TrackerEvent::TaskComplete(task.redacted()));
     }
 
-    fn task_success(&self) {
-        let _ = self.tx.send(TrackerEvent::TaskComplete);
+    fn task_skip(&self, task: TrackedTask) {
+        let _ = self.tx.send(TrackerEvent::TaskSkip(task.redacted()));
     }
 
-    fn task_skip(&self) {
-        let _ = self.tx.send(TrackerEvent::TaskSkip);
+    fn task_fail(&self, task: TrackedTask) {
+        let _ = self.tx.send(TrackerEvent::TaskFail(task.redacted()));
     }
 
-    fn task_fail(&self) {
-        let _ = self.tx.send(TrackerEvent::TaskFail);
+    fn file_diff(&self, diff: String) {
+        let _ = self.tx.send(TrackerEvent::FileDiff(secrets::redact(diff)));
     }
 
-    fn finish_success(&self) {
-        let _ = self.tx.send(TrackerEvent::BatchSuccess);
+    fn finish_success(&self, recap: Vec<TaskRecap>) {
+        let _ = self
+            .tx
+            .send(TrackerEvent::BatchSuccess(redact_recap(recap)));
     }
 
-    fn finish_fail(&self) {
-        let _ = self.tx.send(TrackerEvent::TaskFail);
+    fn finish_fail(&self, recap: Vec<TaskRecap>) {
+        let _ = self.tx.send(TrackerEvent::BatchFail(redact_recap(recap)));
     }
 
     fn suspend_bars(&self) {
@@ -177,6 +296,16 @@ impl Tracker for EventSource {
     }
 }
 
+fn redact_recap(recap: Vec<TaskRecap>) -> Vec<TaskRecap> {
+    recap
+        .into_iter()
+        .map(|r| TaskRecap {
+            task: secrets::redact(r.task),
+            ..r
+        })
+        .collect()
+}
+
 pub struct SinkHandle {
     handle: JoinHandle<()>,
 }
@@ -301,6 +430,32 @@ impl Debug for RemoteWriter {
     }
 }
 
+impl RemoteWriter {
+    /// Send the prompt to the client and wait for its decision. Aborts if
+    /// the connection fails.
+    fn prompt_step(&self, prompt: &StepPrompt) -> StepDecision {
+        let w = &mut *self.out.lock().unwrap();
+        TRACKER_RUNTIME.block_on(async move {
+            let sent = w
+                .send(HpgMessage::ExecServer(ExecServerMessage::StepPrompt(
+                    prompt.clone(),
+                )))
+                .await;
+            if sent.is_err() {
+                return StepDecision::Abort;
+            }
+            while let Some(msg) = w.next().await {
+                match msg {
+                    Ok(HpgMessage::StepDecision(d)) => return d,
+                    Ok(_) => continue,
+                    Err(_) => break,
+                }
+            }
+            StepDecision::Abort
+        })
+    }
+}
+
 impl EventWriter for RemoteWriter {
     fn event(&self, ev: &TrackerEvent) {
         let w = &mut *self.out.lock().unwrap();
@@ -336,12 +491,13 @@ impl EventWriter for PrettyTracker {
                 }
             }
             TrackerEvent::BatchStart(count) => self.run(*count),
-            TrackerEvent::BatchSuccess => self.finish_success(),
-            TrackerEvent::BatchFail => self.finish_fail(),
+            TrackerEvent::BatchSuccess(recap) => self.finish_success(recap),
+            TrackerEvent::BatchFail(recap) => self.finish_fail(recap),
             TrackerEvent::TaskStart(s) => self.task(s.clone()),
-            TrackerEvent::TaskComplete => self.task_success(),
-            TrackerEvent::TaskFail => self.task_fail(),
-            TrackerEvent::TaskSkip => self.task_skip(),
+            TrackerEvent::TaskComplete(s) => self.task_success(s),
+            TrackerEvent::TaskFail(s) => self.task_fail(s),
+            TrackerEvent::TaskSkip(s) => self.task_skip(s),
+            TrackerEvent::FileDiff(d) => self.file_diff(d),
             TrackerEvent::ProgressStart(count) => self.progressbar(*count),
             TrackerEvent::ProgressInc(msg) => self.progressbar_progress(msg.clone()),
             TrackerEvent::ProgressFinish(msg) => self.progressbar_finish(msg.clone()),